@@ -0,0 +1,395 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+// An integer of any size, used once a Lox int does not fit in 64 bits anymore.
+// The magnitude is stored in base 2^32, least significant limb first:
+/*
+    2^40 + 7  ->  limbs = [7, 256]      because 7 + 256 * 2^32 == 2^40 + 7
+    -5        ->  negative = true, limbs = [5]
+    0         ->  negative = false, limbs = []
+*/
+// There are never zero limbs at the end, so every number has exactly one representation
+// and the derived equality is the numeric one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BigInt {
+    negative: bool,
+    limbs: Vec<u32>,
+}
+
+impl BigInt {
+    pub fn from_i64(n: i64) -> Self {
+        Self::from_magnitude(n < 0, vec![n.unsigned_abs() as u32, (n.unsigned_abs() >> 32) as u32])
+    }
+
+    // Only floats without a fractional part have an exact integer value.
+    pub fn from_f64(n: f64) -> Option<Self> {
+        if !n.is_finite() || n.fract() != 0.0 {
+            return None;
+        }
+        // A float is mantissa * 2^exponent, with 52 stored bits plus the implicit leading one.
+        let bits = n.to_bits();
+        let exponent = ((bits >> 52) & 0x7ff) as i64 - 1075;
+        let mantissa = if (bits >> 52) & 0x7ff == 0 { 0 } else { (bits & ((1 << 52) - 1)) | (1 << 52) };
+        let mut limbs = vec![mantissa as u32, (mantissa >> 32) as u32];
+        if exponent >= 0 {
+            limbs = shift_left(&limbs, exponent as usize);
+        } else {
+            // The fraction is zero, so the shifted out bits are all zeros.
+            limbs = shift_right(&limbs, (-exponent) as usize);
+        }
+        Some(Self::from_magnitude(n < 0.0, limbs))
+    }
+
+    // Decimal digits only, the sign is a separate operator in Lox.
+    pub fn parse(digits: &str) -> Option<Self> {
+        if digits.is_empty() {
+            return None;
+        }
+        let mut limbs = Vec::new();
+        for c in digits.chars() {
+            let digit = c.to_digit(10)?;
+            mul_add_small(&mut limbs, 10, digit);
+        }
+        Some(Self::from_magnitude(false, limbs))
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.limbs.len() > 2 {
+            return None;
+        }
+        let magnitude = self.limbs.iter().rev().fold(0i128, |acc, &limb| (acc << 32) | limb as i128);
+        let value = if self.negative { -magnitude } else { magnitude };
+        i64::try_from(value).ok()
+    }
+
+    // Rounds when there are more than 53 significant bits, and gives infinity when it is too big.
+    pub fn to_f64(&self) -> f64 {
+        let magnitude = self.limbs.iter().rev().fold(0.0, |acc, &limb| acc * 4294967296.0 + limb as f64);
+        if self.negative { -magnitude } else { magnitude }
+    }
+
+    pub fn neg(&self) -> Self {
+        Self::from_magnitude(!self.negative, self.limbs.clone())
+    }
+
+    pub fn add(&self, other: &Self) -> Self {
+        if self.negative == other.negative {
+            return Self::from_magnitude(self.negative, add_magnitudes(&self.limbs, &other.limbs));
+        }
+        // Different signs: subtract the smaller magnitude from the bigger one, which gives the sign.
+        match compare_magnitudes(&self.limbs, &other.limbs) {
+            Ordering::Less => Self::from_magnitude(other.negative, sub_magnitudes(&other.limbs, &self.limbs)),
+            _ => Self::from_magnitude(self.negative, sub_magnitudes(&self.limbs, &other.limbs)),
+        }
+    }
+
+    pub fn sub(&self, other: &Self) -> Self {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &Self) -> Self {
+        Self::from_magnitude(self.negative != other.negative, mul_magnitudes(&self.limbs, &other.limbs))
+    }
+
+    // Truncating division like for small ints: the quotient rounds towards zero
+    // and the remainder has the sign of the dividend. None when dividing by zero.
+    pub fn div_rem(&self, other: &Self) -> Option<(Self, Self)> {
+        if other.limbs.is_empty() {
+            return None;
+        }
+        let (quotient, remainder) = div_rem_magnitudes(&self.limbs, &other.limbs);
+        Some((
+            Self::from_magnitude(self.negative != other.negative, quotient),
+            Self::from_magnitude(self.negative, remainder),
+        ))
+    }
+
+    fn from_magnitude(negative: bool, mut limbs: Vec<u32>) -> Self {
+        while limbs.last() == Some(&0) {
+            limbs.pop();
+        }
+        // There is no negative zero.
+        let negative = negative && !limbs.is_empty();
+        Self { negative, limbs }
+    }
+
+    pub fn limb_count(&self) -> usize {
+        self.limbs.len()
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare_magnitudes(&self.limbs, &other.limbs),
+            // For negative numbers the bigger magnitude is the smaller number.
+            (true, true) => compare_magnitudes(&other.limbs, &self.limbs),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    // Peel off 9 decimal digits at a time by dividing by 10^9, which fits in one limb.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.limbs.is_empty() {
+            return write!(f, "0");
+        }
+        let mut limbs = self.limbs.clone();
+        let mut chunks = Vec::new();
+        while !limbs.is_empty() {
+            chunks.push(div_rem_small(&mut limbs, 1_000_000_000));
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        let mut chunks = chunks.iter().rev();
+        // Only the leading chunk goes without its zeros.
+        if let Some(first) = chunks.next() {
+            write!(f, "{}", first)?;
+        }
+        for chunk in chunks {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+// The helpers below work on magnitudes, the limbs of a number without its sign.
+
+fn compare_magnitudes(a: &[u32], b: &[u32]) -> Ordering {
+    // Neither has trailing zero limbs, so more limbs means bigger.
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u64;
+    for i in 0..a.len().max(b.len()) {
+        let sum = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        result.push(sum as u32);
+        carry = sum >> 32;
+    }
+    result.push(carry as u32);
+    result
+}
+
+// a - b, where a must be at least as big as b.
+fn sub_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, &limb) in a.iter().enumerate() {
+        let mut difference = limb as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if difference < 0 {
+            difference += 1 << 32;
+            borrow = 1;
+        }
+        result.push(difference as u32);
+    }
+    result
+}
+
+// Schoolbook multiplication, like on paper but with 32-bit digits.
+fn mul_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = vec![0u32; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, &y) in b.iter().enumerate() {
+            let product = x as u64 * y as u64 + result[i + j] as u64 + carry;
+            result[i + j] = product as u32;
+            carry = product >> 32;
+        }
+        result[i + b.len()] = carry as u32;
+    }
+    result
+}
+
+// Long division one bit at a time. It is slow compared to real bignum libraries,
+// but simple, and fast enough for the numbers a Lox script deals with.
+fn div_rem_magnitudes(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let mut quotient = vec![0u32; a.len()];
+    let mut remainder: Vec<u32> = Vec::new();
+    for bit in (0..a.len() * 32).rev() {
+        remainder = shift_left(&remainder, 1);
+        if (a[bit / 32] >> (bit % 32)) & 1 == 1 {
+            if remainder.is_empty() {
+                remainder.push(1);
+            } else {
+                remainder[0] |= 1;
+            }
+        }
+        while remainder.last() == Some(&0) {
+            remainder.pop();
+        }
+        if compare_magnitudes(&remainder, b) != Ordering::Less {
+            remainder = sub_magnitudes(&remainder, b);
+            while remainder.last() == Some(&0) {
+                remainder.pop();
+            }
+            quotient[bit / 32] |= 1 << (bit % 32);
+        }
+    }
+    (quotient, remainder)
+}
+
+// Divide in place by a single limb and return the remainder.
+fn div_rem_small(limbs: &mut Vec<u32>, divisor: u32) -> u32 {
+    let mut remainder = 0u64;
+    for limb in limbs.iter_mut().rev() {
+        let current = (remainder << 32) | *limb as u64;
+        *limb = (current / divisor as u64) as u32;
+        remainder = current % divisor as u64;
+    }
+    while limbs.last() == Some(&0) {
+        limbs.pop();
+    }
+    remainder as u32
+}
+
+// limbs = limbs * factor + addend, used to read decimal digits.
+fn mul_add_small(limbs: &mut Vec<u32>, factor: u32, addend: u32) {
+    let mut carry = addend as u64;
+    for limb in limbs.iter_mut() {
+        let current = *limb as u64 * factor as u64 + carry;
+        *limb = current as u32;
+        carry = current >> 32;
+    }
+    if carry > 0 {
+        limbs.push(carry as u32);
+    }
+}
+
+fn shift_left(limbs: &[u32], bits: usize) -> Vec<u32> {
+    let mut result = vec![0u32; bits / 32];
+    let shift = bits % 32;
+    let mut carry = 0u32;
+    for &limb in limbs {
+        if shift == 0 {
+            result.push(limb);
+        } else {
+            result.push((limb << shift) | carry);
+            carry = limb >> (32 - shift);
+        }
+    }
+    result.push(carry);
+    result
+}
+
+fn shift_right(limbs: &[u32], bits: usize) -> Vec<u32> {
+    let skipped = bits / 32;
+    if skipped >= limbs.len() {
+        return Vec::new();
+    }
+    let shift = bits % 32;
+    let limbs = &limbs[skipped..];
+    (0..limbs.len())
+        .map(|i| {
+            let high = limbs.get(i + 1).copied().unwrap_or(0);
+            if shift == 0 { limbs[i] } else { (limbs[i] >> shift) | (high << (32 - shift)) }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(digits: &str) -> BigInt {
+        match digits.strip_prefix('-') {
+            Some(digits) => BigInt::parse(digits).expect("Valid digits").neg(),
+            None => BigInt::parse(digits).expect("Valid digits"),
+        }
+    }
+
+    #[test]
+    fn add_carries_across_limbs() {
+        let sum = big("4294967295").add(&big("1"));
+        assert_eq!(sum.limbs, [0, 1]);
+        assert_eq!(sum.to_string(), "4294967296");
+
+        // The carry ripples through every limb and needs a new one at the end.
+        let sum = big("18446744073709551615").add(&big("1"));
+        assert_eq!(sum.limbs, [0, 0, 1]);
+        assert_eq!(sum.to_string(), "18446744073709551616");
+    }
+
+    #[test]
+    fn sub_borrows_across_limbs() {
+        let difference = big("18446744073709551616").sub(&big("1"));
+        assert_eq!(difference.limbs, [u32::MAX, u32::MAX]);
+        assert_eq!(difference.to_string(), "18446744073709551615");
+    }
+
+    #[test]
+    fn mixed_signs_cross_zero() {
+        assert_eq!(big("5").add(&big("-7")), big("-2"));
+        assert_eq!(big("-5").add(&big("7")), big("2"));
+        assert_eq!(big("-2").sub(&big("-7")), big("5"));
+        assert_eq!(big("18446744073709551616").sub(&big("18446744073709551617")), big("-1"));
+        assert_eq!(big("-18446744073709551616").add(&big("18446744073709551617")), big("1"));
+
+        // Landing exactly on zero gives the one zero there is, not a negative one.
+        let zero = big("-18446744073709551616").add(&big("18446744073709551616"));
+        assert_eq!(zero, BigInt::from_i64(0));
+        assert!(!zero.negative);
+        assert_eq!(zero.to_string(), "0");
+    }
+
+    #[test]
+    fn mul_carries_and_signs() {
+        let product = big("4294967295").mul(&big("4294967295"));
+        assert_eq!(product.to_string(), "18446744065119617025");
+        assert_eq!(big("-3").mul(&big("18446744073709551616")), big("-55340232221128654848"));
+        assert_eq!(big("-3").mul(&big("-3")), big("9"));
+    }
+
+    #[test]
+    fn div_rem_truncates_towards_zero() {
+        let div_rem = |a: &str, b: &str| big(a).div_rem(&big(b)).expect("Not dividing by zero");
+        assert_eq!(div_rem("7", "2"), (big("3"), big("1")));
+        assert_eq!(div_rem("-7", "2"), (big("-3"), big("-1")));
+        assert_eq!(div_rem("7", "-2"), (big("-3"), big("1")));
+        assert_eq!(div_rem("-7", "-2"), (big("3"), big("-1")));
+        assert_eq!(
+            div_rem("-100000000000000000001", "10000000000"),
+            (big("-10000000000"), big("-1"))
+        );
+        assert_eq!(div_rem("-3", "18446744073709551616"), (big("0"), big("-3")));
+        assert_eq!(big("1").div_rem(&big("0")), None);
+    }
+
+    #[test]
+    fn from_f64_at_the_edge_of_i64() {
+        let two_to_63 = BigInt::from_f64(9223372036854775808.0).expect("An integer");
+        assert_eq!(two_to_63.limbs, [0, 1 << 31]);
+        assert_eq!(two_to_63.to_string(), "9223372036854775808");
+        assert_eq!(two_to_63.to_i64(), None);
+        assert_eq!(BigInt::from_f64(-9223372036854775808.0).and_then(|n| n.to_i64()), Some(i64::MIN));
+
+        let two_to_64 = BigInt::from_f64(18446744073709551616.0).expect("An integer");
+        assert_eq!(two_to_64.limbs, [0, 0, 1]);
+        assert_eq!(two_to_64.to_string(), "18446744073709551616");
+        assert_eq!(two_to_64.to_f64(), 18446744073709551616.0);
+
+        assert_eq!(BigInt::from_f64(0.5), None);
+        assert_eq!(BigInt::from_f64(f64::INFINITY), None);
+        assert_eq!(BigInt::from_f64(-0.0), Some(BigInt::from_i64(0)));
+    }
+
+    #[test]
+    fn display_pads_inner_chunks() {
+        // 10^18 + 1 is printed in the chunks 1, 000000000 and 000000001.
+        assert_eq!(big("1000000000000000001").to_string(), "1000000000000000001");
+        assert_eq!(big("-1000000000000000000").to_string(), "-1000000000000000000");
+        assert_eq!(big("1000000000").to_string(), "1000000000");
+        assert_eq!(BigInt::from_i64(i64::MIN).to_string(), "-9223372036854775808");
+    }
+}
@@ -2,11 +2,33 @@ use crate::common::Value;
 use crate::opcode::OpCode;
 use crate::value::ValueArray;
 
+// One entry of a chunk's handler table, written by the compiler for every `try` statement.
+// When an exception is thrown while ip is in [start, end), the VM cuts the stack back
+// to `stack_depth` locals, pushes the exception and continues at `target`.
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionHandler {
+    pub start: usize,
+    pub end: usize,
+    pub target: usize,
+    pub stack_depth: usize, // Locals of the function that were alive at the `try`
+    pub is_finally: bool, // A finally block also runs for `return`, a catch block only for exceptions
+}
+
+// A finally block runs with two hidden locals, [ value ][ completion ], where the completion says
+// how the protected code was left. OP_END_FINALLY then carries on with what was interrupted.
+pub const COMPLETION_NORMAL: f64 = 0.0; // Fell off the end, value is nil
+pub const COMPLETION_THROW: f64 = 1.0; // value is the exception, rethrow it
+pub const COMPLETION_RETURN: f64 = 2.0; // value is the return value, keep returning
+
+// In OP_SHUFFLE's operands, a parameter the call left out. There are at most 255 arguments, so no argument has this index.
+pub const NOT_GIVEN: u8 = u8::MAX;
+
 #[derive(Debug)]
 pub struct Chunk {
     code: Vec<u8>, // The bytecode instructions
     constants: ValueArray, // Pool of literal values
     lines: Vec<usize>, // Line number of a particular bytecode
+    handlers: Vec<ExceptionHandler>, // Innermost `try` first, so the first match wins
 }
 
 impl Chunk {
@@ -15,6 +37,7 @@ impl Chunk {
             code: Vec::new(),
             constants: ValueArray::new(),
             lines: Vec::new(),
+            handlers: Vec::new(),
         }
     }
 
@@ -27,6 +50,15 @@ impl Chunk {
         self.write(opcode.into(), line);
     }
 
+    // Overwrite an already written byte, used to fill in jump offsets once we know them.
+    pub fn patch(&mut self, offset: usize, byte: u8) {
+        self.code[offset] = byte;
+    }
+
+    pub fn add_handler(&mut self, handler: ExceptionHandler) {
+        self.handlers.push(handler);
+    }
+
     pub fn add_constant(&mut self, value: Value) -> usize {
         let index = self.constants.count();
         self.constants.write(value);
@@ -52,6 +84,10 @@ impl Chunk {
         &self.lines
     }
 
+    pub fn handlers(&self) -> &[ExceptionHandler] {
+        &self.handlers
+    }
+
     pub fn constants(&self) -> &ValueArray {
         &self.constants
     }
@@ -1,8 +1,20 @@
-#[derive(Debug, Clone, PartialEq)]
+use crate::number;
+use crate::object::{Obj, ObjRef};
+
+// Set to true to dump the bytecode of every chunk right after it is compiled.
+pub const DEBUG_PRINT_CODE: bool = false;
+// Set to true to print the stack and the current instruction before executing it.
+pub const DEBUG_TRACE_EXECUTION: bool = false;
+
+// Strings are interned, so two equal strings are always the same object
+// and comparing the handles is enough to compare their content.
+#[derive(Debug, Clone, Copy)]
 pub enum Value {
-    Number(f64),
+    Int(i64), // Integer literals and the results of integer arithmetic
+    Number(f64), // A float: literals with a '.', or anything computed with one
     Bool(bool),
     Nil,
+    Obj(ObjRef), // Heap-allocated values such as strings
 }
 
 impl Value {
@@ -10,24 +22,101 @@ impl Value {
         match self {
             Value::Nil => true,
             Value::Bool(b) => !b,
-            Value::Number(_) => false,
+            Value::Int(_) | Value::Number(_) => false,
+            Value::Obj(_) => false,
         }
     }
-    
+
+    // Any number as a float, ints included.
     pub fn as_number(&self) -> Option<f64> {
         match self {
+            Value::Int(n) => Some(*n as f64),
             Value::Number(n) => Some(*n),
+            Value::Obj(obj) => obj.as_bigint().map(|big| big.value.to_f64()),
+            _ => None,
+        }
+    }
+
+    // An int, or a float without a fractional part.
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            Value::Number(n) => number::float_to_int(*n),
             _ => None,
         }
     }
+
+    pub fn as_string(&self) -> Option<&str> {
+        match self {
+            Value::Obj(obj) => obj.as_string().map(|s| s.chars.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn is_string(&self) -> bool {
+        self.as_string().is_some()
+    }
+
+    // Whether this value can be a map key. Strings compare by content (they are interned),
+    // the other objects are mutable or compare by identity, so they are not allowed.
+    // NaN is not equal to itself, it could never be found again.
+    pub fn is_hashable(&self) -> bool {
+        match self {
+            Value::Int(_) => true,
+            Value::Number(n) => !n.is_nan(),
+            Value::Bool(_) | Value::Nil => true,
+            Value::Obj(_) => self.is_string(),
+        }
+    }
+
+    // The name the `type` native returns, also handy in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Number(_) => "float",
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::Obj(obj) => match &**obj {
+                Obj::String(_) => "string",
+                // Users don't need to know when an int became big.
+                Obj::BigInt(_) => "int",
+                Obj::Function(_) | Obj::Closure(_) | Obj::BoundMethod(_) | Obj::Native(_) => "function",
+                Obj::Upvalue(_) => "upvalue",
+                Obj::Class(_) => "class",
+                Obj::Instance(_) => "instance",
+                Obj::List(_) => "list",
+                Obj::Map(_) => "map",
+                Obj::Module(_) => "module",
+                Obj::Iterator(_) => "iterator",
+                Obj::Generator(_) => "generator",
+                Obj::Fiber(_) => "fiber",
+                Obj::Channel(_) => "channel",
+            },
+        }
+    }
 }
 
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Value::Int(n) => write!(f, "{}", n),
             Value::Number(n) => write!(f, "{}", n),
             Value::Bool(b) => write!(f, "{}", b),
             Value::Nil => write!(f, "nil"),
+            Value::Obj(obj) => write!(f, "{}", **obj),
         }
     }
-}
\ No newline at end of file
+}
+
+// Numbers compare by value whatever their type, so 1 == 1.0 and two bignums are equal when their digits are.
+// Everything else compares like before: strings are interned, so comparing the handles is enough.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Obj(a), Value::Obj(b)) if a == b => true,
+            _ => number::equal(*self, *other),
+        }
+    }
+}
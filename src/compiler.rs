@@ -1,7 +1,14 @@
 use crate::scanner::{Scanner, Token, TokenType};
-use crate::chunk::Chunk;
+use crate::bigint::BigInt;
+use crate::chunk::{Chunk, ExceptionHandler, COMPLETION_NORMAL, NOT_GIVEN};
 use crate::opcode::OpCode;
-use crate::common::Value;
+use crate::common::{Value, DEBUG_PRINT_CODE};
+use crate::debug::disassemble_chunk;
+use crate::object::{Obj, ObjFunction, ObjRef};
+use crate::number::{self, Computed};
+use crate::vm::VM;
+use std::collections::HashMap;
+use std::iter;
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 enum Precedence {
@@ -11,11 +18,15 @@ enum Precedence {
     And = 3,         // and (binary) 
     Equality = 4,    // == != (binary)
     Comparison = 5,  // < > <= >= (binary)
-    Term = 6,        // + - (binary)
-    Factor = 7,      // * / (binary)
-    Unary = 8,       // ! - (unary operators)
-    Call = 9,        // . () 
-    Primary = 10,
+    BitOr = 6,       // | (binary)
+    BitXor = 7,      // ^ (binary)
+    BitAnd = 8,      // & (binary)
+    Shift = 9,       // << >> (binary)
+    Term = 10,       // + - (binary)
+    Factor = 11,     // * / % (binary)
+    Unary = 12,      // ! - ~ (unary operators)
+    Call = 13,       // . () 
+    Primary = 14,
 }
 
 impl Precedence {
@@ -26,7 +37,11 @@ impl Precedence {
             Precedence::Or => Precedence::And,
             Precedence::And => Precedence::Equality,
             Precedence::Equality => Precedence::Comparison,
-            Precedence::Comparison => Precedence::Term,
+            Precedence::Comparison => Precedence::BitOr,
+            Precedence::BitOr => Precedence::BitXor,
+            Precedence::BitXor => Precedence::BitAnd,
+            Precedence::BitAnd => Precedence::Shift,
+            Precedence::Shift => Precedence::Term,
             Precedence::Term => Precedence::Factor,
             Precedence::Factor => Precedence::Unary,
             Precedence::Unary => Precedence::Call,
@@ -36,12 +51,14 @@ impl Precedence {
     }
 }
 
-type ParseFn = fn(&mut Compiler);
+// The bool tells the parse function whether an `=` after it may be treated as an assignment.
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool);
 
-struct ParseRule {
-    prefix: Option<ParseFn>,
+#[derive(Clone, Copy)]
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
     // the function to compile a prefix expression starting with a token of that type.
-    infix: Option<ParseFn>,
+    infix: Option<ParseFn<'a>>,
     // the function to compile an infix expression whose left operand is followed by a token of that type.
     precedence: Precedence,
     // the precedence of an infix expression that uses that token as an operator.
@@ -73,23 +90,181 @@ impl Parser {
     }
 }
 
-struct Compiler {
+// Anonymous functions still need a name for printing them and for stack traces.
+const LAMBDA_NAME: &str = "lambda";
+
+// Local slots are addressed with a single byte operand.
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+
+struct Local {
+    name: Token,
+    depth: Option<usize>, // None while the variable is declared but its initializer is not done yet
+    is_captured: bool, // Captured by a closure, so it must be closed instead of popped at scope exit
+    function: Option<ObjRef>, // The function a `fun` declaration bound it to, as long as nothing is assigned to it
+    called_by_name: bool, // A call with named arguments relied on `function`, so it must not be assigned anymore
+    is_const: bool, // Declared with `const`, assigning to it is a compile error
+    inline_value: Option<Value>, // A const initialized with a literal: reading it just loads the literal
+}
+
+// How a closure finds a captured variable when it is created:
+// either a local of the directly enclosing function, or one of that function's own upvalues.
+#[derive(Clone, Copy, PartialEq)]
+struct Upvalue {
+    index: u8,
+    is_local: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionType {
+    Function,
+    Generator, // A `fun*` function, the only kind that can yield
+    Initializer, // The init() method of a class, always returns `this`
+    Method,
+    Script, // The implicit function wrapping the top-level code
+}
+
+// The state needed to compile one function body.
+// Functions can be nested, so the compiler keeps a stack of these.
+struct FunctionCompiler {
+    function: ObjFunction, // Moved onto the heap once the body is done
+    function_type: FunctionType,
+    locals: Vec<Local>, // Mirrors the function's stack window: locals[i] lives in slot i
+    upvalues: Vec<Upvalue>, // Variables this function captures from enclosing functions
+    scope_depth: usize, // 0 is the global scope, every block adds 1
+}
+
+impl FunctionCompiler {
+    fn new(function_type: FunctionType, name: Option<ObjRef>, module: ObjRef) -> Self {
+        // Slot 0 holds the function being called, so user locals start at slot 1.
+        // In methods, slot 0 holds the receiver instead, and it can be referred to as `this`.
+        let slot_zero_name = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            FunctionType::Function | FunctionType::Generator | FunctionType::Script => "",
+        };
+        let slot_zero = Local {
+            name: Compiler::synthetic_token(slot_zero_name),
+            depth: Some(0),
+            is_captured: false,
+            function: None,
+            called_by_name: false,
+            is_const: false,
+            inline_value: None,
+        };
+        Self {
+            function: ObjFunction::new(name, module),
+            function_type,
+            locals: vec![slot_zero],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+        }
+    }
+}
+
+// One per class declaration we are inside of, innermost last.
+struct ClassCompiler {
+    has_superclass: bool, // Whether `super` can be used in this class
+}
+
+// A match pattern. They are parsed into this first, because the code testing them
+// can only be chosen once every arm of the match is known.
+enum Pattern {
+    Wildcard, // _
+    Binding(Token), // x: matches anything and gives it a name in the arm
+    Literal(Value), // 1, -2.5, "a", true, nil
+    Range(Value, Value), // 1..5, both ends included
+    List(Vec<Pattern>), // [p, q]: a list of exactly that many items, each matching its pattern
+    Or(Vec<Pattern>), // p | q
+}
+
+impl Pattern {
+    // Matches every value: `_`, a name, or alternatives with one of those among them, like `1 | _`.
+    fn is_irrefutable(&self) -> bool {
+        match self {
+            Pattern::Wildcard | Pattern::Binding(_) => true,
+            Pattern::Or(alternatives) => alternatives.iter().any(Pattern::is_irrefutable),
+            _ => false,
+        }
+    }
+
+    fn binds(&self) -> bool {
+        match self {
+            Pattern::Binding(_) => true,
+            Pattern::List(items) | Pattern::Or(items) => items.iter().any(Pattern::binds),
+            _ => false,
+        }
+    }
+
+    // The literals this pattern is made of, if that is all it is: `1 | 2` but not `1 | [x]`.
+    fn literals(&self) -> Option<Vec<Value>> {
+        match self {
+            Pattern::Literal(value) => Some(vec![*value]),
+            Pattern::Or(alternatives) => alternatives.iter().map(|alternative| match alternative {
+                Pattern::Literal(value) => Some(*value),
+                _ => None,
+            }).collect(),
+            _ => None,
+        }
+    }
+
+    // Every variable it binds, with the indexes leading to its value: [a, [b, _]] binds a at [0] and b at [1, 1].
+    fn bindings(&self, path: &mut Vec<usize>, bindings: &mut Vec<(Token, Vec<usize>)>) {
+        match self {
+            Pattern::Binding(name) => bindings.push((name.clone(), path.clone())),
+            Pattern::List(items) => {
+                for (index, item) in items.iter().enumerate() {
+                    path.push(index);
+                    item.bindings(path, bindings);
+                    path.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+struct MatchArm {
+    pattern: Pattern,
+    body: usize, // Where the arm's code starts
+}
+
+// Dense int arms are dispatched with one OP_JUMP_TABLE when there are at least this many values.
+const JUMP_TABLE_MIN_CASES: usize = 4;
+
+struct Compiler<'a> {
     scanner: Scanner,
     parser: Parser,
-    compiling_chunk: Chunk,
+    vm: &'a mut VM, // String literals and functions are allocated on the VM's heap
+    function_compilers: Vec<FunctionCompiler>, // The innermost function being compiled is last
+    class_compilers: Vec<ClassCompiler>, // Empty when we are not inside a class body
+    module: ObjRef, // The module this source file becomes
+    callee: Option<(u8, ObjRef)>, // The local and its function, when the call about to be compiled calls a known function
+    global_consts: HashMap<String, Option<Value>>, // The global consts declared so far, with the value to inline if any
 }
 
-impl Compiler {
-    fn new(scanner: Scanner) -> Self {
+impl<'a> Compiler<'a> {
+    fn new(scanner: Scanner, vm: &'a mut VM, module: ObjRef) -> Self {
         Self {
             scanner,
             parser: Parser::new(),
-            compiling_chunk: Chunk::new(),
+            vm,
+            function_compilers: vec![FunctionCompiler::new(FunctionType::Script, None, module)],
+            class_compilers: Vec::new(),
+            module,
+            callee: None,
+            global_consts: HashMap::new(),
         }
     }
 
+    fn current(&self) -> &FunctionCompiler {
+        self.function_compilers.last().expect("Always compiling at least the script")
+    }
+
+    fn current_mut(&mut self) -> &mut FunctionCompiler {
+        self.function_compilers.last_mut().expect("Always compiling at least the script")
+    }
+
     fn current_chunk(&mut self) -> &mut Chunk {
-        &mut self.compiling_chunk
+        &mut self.current_mut().function.chunk
     }
 
     fn advance(&mut self) {
@@ -107,59 +282,1339 @@ impl Compiler {
         }
     }
 
-    fn error_at_current(&mut self, message: &str) {
-        self.error_at(&self.parser.current.clone(), message);
+    fn error_at_current(&mut self, message: &str) {
+        self.error_at(&self.parser.current.clone(), message);
+    }
+
+    fn error(&mut self, message: &str) {
+        self.error_at(&self.parser.previous.clone(), message);
+    }
+
+    // Reported like an error, but the code still compiles and runs.
+    fn warning_at(&self, token: &Token, message: &str) {
+        if !self.parser.panic_mode {
+            eprintln!("[line {}] Warning at '{}': {}", token.line, token.lexeme, message);
+        }
+    }
+
+    fn error_at(&mut self, token: &Token, message: &str) {
+        if self.parser.panic_mode {
+            return;
+        }
+        self.parser.panic_mode = true;
+        eprint!("[line {}] Error", token.line);
+
+        if token.token_type == TokenType::Eof {
+            eprint!(" at end");
+        } else if token.token_type == TokenType::Error {
+            // Nothing
+        } else {
+            eprint!(" at '{}'", token.lexeme);
+        }
+
+        eprintln!(": {}", message);
+        self.parser.had_error = true;
+    }
+
+    fn consume(&mut self, token_type: TokenType, message: &str) {
+        // Consume a token of the expected type, or report an error
+        if self.parser.current.token_type == token_type {
+            self.advance();
+            return;
+        }
+        self.error_at_current(message);
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.parser.current.token_type == token_type
+    }
+
+    // `as`, `from` and `in` are only keywords where import and for-in expect them,
+    // everywhere else they are ordinary names: `var from = 0;` still works.
+    fn check_contextual(&self, keyword: &str) -> bool {
+        self.check(TokenType::Identifier) && self.parser.current.lexeme == keyword
+    }
+
+    fn match_contextual(&mut self, keyword: &str) -> bool {
+        if !self.check_contextual(keyword) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn consume_contextual(&mut self, keyword: &str, message: &str) {
+        if !self.match_contextual(keyword) {
+            self.error_at_current(message);
+        }
+    }
+
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        // Like consume(), but it is fine if the current token is not the one we want
+        if !self.check(token_type) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.parser.previous.line;
+        self.current_chunk().write(byte, line);
+    }
+
+    fn emit_opcode(&mut self, opcode: OpCode) {
+        let line = self.parser.previous.line;
+        self.current_chunk().write_opcode(opcode, line);
+    }
+
+    fn emit_return(&mut self) {
+        if self.current().function_type == FunctionType::Initializer {
+            // An initializer implicitly returns the instance, which lives in slot 0.
+            self.emit_opcode(OpCode::GetLocal);
+            self.emit_byte(0);
+        } else {
+            // A function without a return statement implicitly returns nil.
+            self.emit_opcode(OpCode::Nil);
+        }
+        self.emit_opcode(OpCode::Return);
+    }
+
+    fn make_constant(&mut self, value: Value) -> u8 {
+        // The operand of OP_CONSTANT is a single byte, so one chunk can only hold 256 constants.
+        // Keep it alive in case a garbage collection happens before the function is finished.
+        self.vm.add_compiler_root(value);
+        let constant = self.current_chunk().add_constant(value);
+        if constant > u8::MAX as usize {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+        constant as u8
+    }
+
+    // Emit a jump with a placeholder offset and return where the offset is, so it can be patched later.
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_opcode(instruction);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        self.current_chunk().count() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the bytecode for the jump offset itself.
+        let jump = self.current_chunk().count() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+
+        let [high, low] = (jump as u16).to_be_bytes();
+        self.current_chunk().patch(offset, high);
+        self.current_chunk().patch(offset + 1, low);
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_opcode(OpCode::Loop);
+
+        // +2 to also jump back over the operand of OP_LOOP.
+        let offset = self.current_chunk().count() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+
+        let [high, low] = (offset as u16).to_be_bytes();
+        self.emit_byte(high);
+        self.emit_byte(low);
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let constant = self.make_constant(value);
+        self.emit_opcode(OpCode::Constant);
+        self.emit_byte(constant);
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    /*
+        program     -> declaration* EOF ;
+        declaration -> classDecl | funDecl | varDecl | constDecl | exportDecl | importStmt | statement ;
+        classDecl   -> "class" IDENTIFIER ( "<" IDENTIFIER )? "{" function* "}" ;
+        funDecl     -> "fun" "*"? function ;
+        function    -> IDENTIFIER "(" parameters? ")" block ;
+        varDecl     -> "var" IDENTIFIER ( "=" expression )? ";" ;
+        constDecl   -> "const" IDENTIFIER "=" expression ";" ;
+        exportDecl  -> "export" ( classDecl | funDecl | varDecl | constDecl ) ;
+        importStmt  -> "import" STRING ( "as" IDENTIFIER )? ";"
+                     | "import" "{" IDENTIFIER ( "," IDENTIFIER )* "}" "from" STRING ";" ;
+        statement   -> printStmt | exprStmt | ifStmt | whileStmt | forStmt | returnStmt
+                     | throwStmt | tryStmt | block ;
+        returnStmt  -> "return" expression? ";" ;
+        throwStmt   -> "throw" expression ";" ;
+        tryStmt     -> "try" block ( "catch" "(" IDENTIFIER ")" block )? ( "finally" block )? ;
+        ifStmt      -> "if" "(" expression ")" statement ( "else" statement )? ;
+        whileStmt   -> "while" "(" expression ")" statement ;
+        forStmt     -> "for" "(" ( varDecl | exprStmt | ";" ) expression? ";" expression? ")" statement ;
+        block       -> "{" declaration* "}" ;
+    */
+    fn declaration(&mut self) {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.check(TokenType::Fun) && !self.lambda_follows() {
+            self.advance();
+            self.fun_declaration();
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else if self.match_token(TokenType::Const) {
+            self.const_declaration();
+        } else if self.match_token(TokenType::Export) {
+            self.export_declaration();
+        } else if self.match_token(TokenType::Import) {
+            self.import_statement();
+        } else {
+            self.statement();
+        }
+
+        // After an error, skip tokens until we reach something that looks like
+        // the start of a new statement so that we can report more than one error.
+        if self.parser.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    // Whether the current `fun` starts an anonymous function, which is an expression statement.
+    // Only the token after it, or after its star, tells the two apart:
+    /*
+        fun (a) { ... }      fun* () { ... }      anonymous
+        fun f(a) { ... }     fun* gen() { ... }   declaration
+    */
+    fn lambda_follows(&mut self) -> bool {
+        let checkpoint = self.scanner.checkpoint();
+        let mut token_type = self.scanner.scan_token().token_type;
+        if token_type == TokenType::Star {
+            token_type = self.scanner.scan_token().token_type;
+        }
+        self.scanner.restore(checkpoint);
+        token_type == TokenType::LeftParen
+    }
+
+    fn is_top_level(&self) -> bool {
+        self.function_compilers.len() == 1 && self.current().scope_depth == 0
+    }
+
+    // export fun f() {} -> a global like any other, the module also remembers that importers may see it.
+    fn export_declaration(&mut self) {
+        if !self.is_top_level() {
+            self.error("Can only export from the top level of a module.");
+        }
+
+        // Right after the keyword comes the name being declared.
+        let keyword = self.parser.current.token_type;
+        if !matches!(keyword, TokenType::Class | TokenType::Fun | TokenType::Var | TokenType::Const) {
+            self.error_at_current("Expect 'class', 'fun', 'var' or 'const' after 'export'.");
+            return;
+        }
+        self.advance();
+        // A generator's name comes after its star: `export fun* gen() {}`.
+        let name = if keyword == TokenType::Fun && self.check(TokenType::Star) {
+            self.scanner.peek_token()
+        } else {
+            self.parser.current.clone()
+        };
+        match keyword {
+            TokenType::Class => self.class_declaration(),
+            TokenType::Fun => self.fun_declaration(),
+            TokenType::Const => self.const_declaration(),
+            _ => self.var_declaration(),
+        }
+
+        // The module is a compiler root, so the name is safe once it is in the exports table.
+        let name = self.vm.alloc_string(name.lexeme);
+        let module = self.module.as_module().expect("Compiling into a module");
+        module.exports.borrow_mut().set(Value::Obj(name), Value::Bool(true));
+        self.vm.remeasure(self.module);
+    }
+
+    // The module ends up on the stack, then it is either bound to one name or picked apart:
+    /*
+        import "util.lox" as util;       OP_IMPORT "util.lox", OP_POP, OP_DEFINE_GLOBAL util
+        import { a, b } from "util.lox"; OP_IMPORT "util.lox", OP_POP,
+                                         OP_IMPORT_NAME a, OP_DEFINE_GLOBAL a,
+                                         OP_IMPORT_NAME b, OP_DEFINE_GLOBAL b, OP_POP
+    */
+    // OP_IMPORT leaves [ module ][ result of its top-level code ], hence the first OP_POP.
+    fn import_statement(&mut self) {
+        if !self.is_top_level() {
+            self.error("Can only import at the top level of a module.");
+        }
+
+        if self.match_token(TokenType::LeftBrace) {
+            let mut names = Vec::new();
+            loop {
+                self.consume(TokenType::Identifier, "Expect name to import.");
+                names.push(self.parser.previous.clone());
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+            self.consume(TokenType::RightBrace, "Expect '}' after imported names.");
+            self.consume_contextual("from", "Expect 'from' after imported names.");
+            self.consume(TokenType::String, "Expect module path.");
+            self.emit_import();
+
+            for name in names {
+                let name_constant = self.identifier_constant(&name);
+                self.emit_opcode(OpCode::ImportName);
+                self.emit_byte(name_constant);
+                self.emit_opcode(OpCode::DefineGlobal);
+                self.emit_byte(name_constant);
+            }
+            self.emit_opcode(OpCode::Pop);
+        } else {
+            self.consume(TokenType::String, "Expect module path or '{' after 'import'.");
+            self.emit_import();
+            if self.match_contextual("as") {
+                let global = self.parse_variable("Expect module name after 'as'.");
+                self.define_variable(global);
+            } else {
+                // Imported only for what its top-level code does.
+                self.emit_opcode(OpCode::Pop);
+            }
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after import.");
+    }
+
+    // The path is the String token just consumed.
+    fn emit_import(&mut self) {
+        let path = self.vm.alloc_string(self.parser.previous.lexeme.clone());
+        let path_constant = self.make_constant(Value::Obj(path));
+        self.emit_opcode(OpCode::Import);
+        self.emit_byte(path_constant);
+        self.emit_opcode(OpCode::Pop);
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.parser.previous.clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_opcode(OpCode::Class);
+        self.emit_byte(name_constant);
+        self.define_variable(name_constant);
+
+        self.class_compilers.push(ClassCompiler { has_superclass: false });
+
+        if self.match_token(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+            if class_name.lexeme == self.parser.previous.lexeme {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // The superclass is stored in a hidden local called `super`, in a scope around the methods,
+            // so every method captures it as an upvalue and `super` always means this class's superclass.
+            self.begin_scope();
+            self.add_local(Self::synthetic_token("super"));
+            self.define_variable(0);
+
+            self.named_variable(&class_name, false);
+            self.emit_opcode(OpCode::Inherit);
+            self.class_compilers.last_mut().expect("Pushed above").has_superclass = true;
+        }
+
+        // Load the class back onto the stack so that OP_METHOD can find it.
+        self.named_variable(&class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_opcode(OpCode::Pop);
+
+        let class_compiler = self.class_compilers.pop().expect("Pushed above");
+        if class_compiler.has_superclass {
+            self.end_scope();
+        }
+    }
+
+    // A token that does not come from the source code, for variables the compiler declares itself.
+    fn synthetic_token(text: &str) -> Token {
+        Token {
+            token_type: TokenType::Identifier,
+            lexeme: text.to_string(),
+            line: 0,
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.parser.previous.clone();
+        let constant = self.identifier_constant(&name);
+
+        let function_type = if name.lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type, &name.lexeme);
+
+        self.emit_opcode(OpCode::Method);
+        self.emit_byte(constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        // `fun* name()` declares a generator.
+        let function_type = if self.match_token(TokenType::Star) {
+            FunctionType::Generator
+        } else {
+            FunctionType::Function
+        };
+        let global = self.parse_variable("Expect function name.");
+        // A function can refer to itself (recursion), so it is initialized before its body is compiled.
+        self.mark_initialized();
+        let name = self.parser.previous.lexeme.clone();
+        let function = self.function(function_type, &name);
+        self.define_variable(global);
+
+        // Calls with named arguments can be checked against its parameters, see call().
+        if self.current().scope_depth > 0 {
+            let local = self.current_mut().locals.last_mut().expect("Just declared");
+            local.function = Some(function);
+        }
+    }
+
+    fn function(&mut self, function_type: FunctionType, name: &str) -> ObjRef {
+        self.begin_function(function_type, name);
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        self.parameters();
+        self.function_body()
+    }
+
+    fn function_body(&mut self) -> ObjRef {
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+        self.end_function()
+    }
+
+    // Start compiling a new function: everything emitted until end_function() goes into its chunk.
+    fn begin_function(&mut self, function_type: FunctionType, name: &str) {
+        let name = self.vm.alloc_string(name.to_string());
+        self.vm.add_compiler_root(Value::Obj(name));
+        self.function_compilers.push(FunctionCompiler::new(function_type, Some(name), self.module));
+        self.current_mut().function.is_generator = function_type == FunctionType::Generator;
+        // No matching end_scope(): the whole compiler state is thrown away at the end.
+        self.begin_scope();
+    }
+
+    // The parameter list after its '(', up to and including the ')':
+    /*
+        (a, b = 2, ...rest)
+    */
+    // Default values are part of the function's own code, they are evaluated on every call that leaves
+    // their parameter out. So `fun f(list = [])` gets a new list each time, and `fun f(a, b = a)` works.
+    fn parameters(&mut self) {
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if self.current().function.parameters.len() == 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let is_rest = self.match_token(TokenType::DotDotDot);
+                let constant = self.parse_variable("Expect parameter name.");
+                let name = self.vm.alloc_string(self.parser.previous.lexeme.clone());
+                self.vm.add_compiler_root(Value::Obj(name));
+                self.current_mut().function.parameters.push(name);
+
+                if !is_rest && self.match_token(TokenType::Equal) {
+                    // Still uninitialized, so the default value can't refer to the parameter itself.
+                    self.default_value();
+                    self.current_mut().function.optional += 1;
+                } else if !is_rest && self.current().function.optional > 0 {
+                    self.error("Parameters after one with a default value need a default value too.");
+                }
+                self.define_variable(constant);
+
+                if is_rest {
+                    // The extra arguments have nowhere else to go.
+                    self.current_mut().function.has_rest = true;
+                    if self.check(TokenType::Comma) {
+                        self.error_at_current("The rest parameter must be the last one.");
+                    }
+                    break;
+                }
+                self.current_mut().function.arity += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+    }
+
+    // A call that leaves the parameter out puts a placeholder in its slot, see VM::call():
+    /*
+        OP_JUMP_IF_GIVEN slot (skip)
+        default value
+        OP_SET_LOCAL slot
+        OP_POP
+        skip:
+    */
+    fn default_value(&mut self) {
+        let slot = (self.current().locals.len() - 1) as u8;
+        self.emit_opcode(OpCode::JumpIfGiven);
+        self.emit_byte(slot);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        let skip = self.current_chunk().count() - 2;
+        self.expression();
+        self.emit_opcode(OpCode::SetLocal);
+        self.emit_byte(slot);
+        self.emit_opcode(OpCode::Pop);
+        self.patch_jump(skip);
+    }
+
+    // Finish the function and emit the code creating its closure in the enclosing function.
+    fn end_function(&mut self) -> ObjRef {
+        let (function, upvalues) = self.end_compiler();
+        let constant = self.make_constant(Value::Obj(function));
+        self.emit_opcode(OpCode::Closure);
+        self.emit_byte(constant);
+
+        // Tell the VM where to find each captured variable when it creates the closure.
+        for upvalue in upvalues {
+            self.emit_byte(if upvalue.is_local { 1 } else { 0 });
+            self.emit_byte(upvalue.index);
+        }
+        function
+    }
+
+    // Finish the innermost function and move it onto the heap.
+    fn end_compiler(&mut self) -> (ObjRef, Vec<Upvalue>) {
+        self.emit_return();
+        let mut compiler = self.function_compilers.pop().expect("Always compiling at least the script");
+        compiler.function.upvalue_count = compiler.upvalues.len();
+
+        if DEBUG_PRINT_CODE && !self.parser.had_error {
+            disassemble_chunk(&compiler.function.chunk, &compiler.function.to_string());
+        }
+
+        (self.vm.alloc(Obj::Function(compiler.function)), compiler.upvalues)
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            // var a; is the same as var a = nil;
+            self.emit_opcode(OpCode::Nil);
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.");
+
+        self.define_variable(global);
+    }
+
+    // Like a var, but it must be initialized and can't be assigned afterwards.
+    fn const_declaration(&mut self) {
+        let global = self.parse_variable("Expect constant name.");
+        let name = self.parser.previous.lexeme.clone();
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        let start = self.current_chunk().count();
+        self.expression();
+        let value = self.literal_since(start);
+        self.consume(TokenType::Semicolon, "Expect ';' after constant declaration.");
+
+        if self.current().scope_depth > 0 {
+            self.mark_initialized();
+            let local = self.current_mut().locals.last_mut().expect("Just declared");
+            local.is_const = true;
+            local.inline_value = value;
+        } else {
+            self.emit_opcode(OpCode::DefineConst);
+            self.emit_byte(global);
+            self.global_consts.insert(name, value);
+        }
+    }
+
+    // The value of the expression compiled from `start` on, if it was a lone literal such as 10, "a" or nil.
+    fn literal_since(&mut self, start: usize) -> Option<Value> {
+        let chunk = self.current_chunk();
+        match chunk.code()[start..] {
+            [constant, index] if constant == u8::from(OpCode::Constant) => chunk.constants().get(index as usize).copied(),
+            [opcode] if opcode == u8::from(OpCode::Nil) => Some(Value::Nil),
+            [opcode] if opcode == u8::from(OpCode::True) => Some(Value::Bool(true)),
+            [opcode] if opcode == u8::from(OpCode::False) => Some(Value::Bool(false)),
+            _ => None,
+        }
+    }
+
+    fn parse_variable(&mut self, error_message: &str) -> u8 {
+        self.consume(TokenType::Identifier, error_message);
+
+        self.declare_variable();
+        // Locals are looked up by stack slot at runtime, they do not need their name in the constant table.
+        if self.current().scope_depth > 0 {
+            return 0;
+        }
+
+        let name = self.parser.previous.clone();
+        self.identifier_constant(&name)
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> u8 {
+        // Global variable names are too big to fit in the bytecode,
+        // so we store the name in the constant table and refer to it by index.
+        let string = Value::Obj(self.vm.alloc_string(name.lexeme.clone()));
+
+        // Reuse the slot if this name is already in the constant table,
+        // otherwise every mention of a variable would use up one of our 256 constants.
+        let constants = self.current_chunk().constants();
+        if let Some(index) = (0..constants.count()).find(|&i| constants.get(i) == Some(&string)) {
+            return index as u8;
+        }
+        self.make_constant(string)
+    }
+
+    fn declare_variable(&mut self) {
+        if self.current().scope_depth == 0 {
+            // The VM would refuse it too, but only once it runs.
+            let name = self.parser.previous.clone();
+            if self.global_consts.contains_key(&name.lexeme) {
+                self.error(&format!("Can't redefine constant '{}'.", name.lexeme));
+            }
+            return;
+        }
+
+        let name = self.parser.previous.clone();
+        // Shadowing a variable from an outer scope is fine, redeclaring one in the same scope is not.
+        let current = self.current();
+        let already_declared = current
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= current.scope_depth))
+            .any(|local| local.name.lexeme == name.lexeme);
+        if already_declared {
+            self.error("Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Token) {
+        if self.current().locals.len() == UINT8_COUNT {
+            self.error("Too many local variables in function.");
+            return;
+        }
+        self.current_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+            function: None,
+            called_by_name: false,
+            is_const: false,
+            inline_value: None,
+        });
+    }
+
+    fn mark_initialized(&mut self) {
+        let current = self.current_mut();
+        // Globals are not tracked in `locals`, so there is nothing to mark.
+        if current.scope_depth == 0 {
+            return;
+        }
+        let depth = current.scope_depth;
+        if let Some(local) = current.locals.last_mut() {
+            local.depth = Some(depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: u8) {
+        if self.current().scope_depth > 0 {
+            // The value of the initializer is already sitting in the local's stack slot.
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_opcode(OpCode::DefineGlobal);
+        self.emit_byte(global);
+    }
+
+    fn statement(&mut self) {
+        if self.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.match_token(TokenType::For) {
+            self.for_statement();
+        } else if self.match_token(TokenType::Throw) {
+            self.throw_statement();
+        } else if self.match_token(TokenType::Yield) {
+            self.yield_statement();
+        } else if self.match_token(TokenType::Match) {
+            self.match_statement();
+        } else if self.match_token(TokenType::Try) {
+            self.try_statement();
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.current_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.current_mut().scope_depth -= 1;
+
+        // Locals declared inside the block go out of scope, so pop them off the stack.
+        loop {
+            let current = self.current();
+            let out_of_scope = current
+                .locals
+                .last()
+                .is_some_and(|local| local.depth.is_some_and(|depth| depth > current.scope_depth));
+            if !out_of_scope {
+                break;
+            }
+            let local = self.current_mut().locals.pop().expect("Checked above");
+            if local.is_captured {
+                // A closure still needs this variable, so hoist it onto the heap.
+                self.emit_opcode(OpCode::CloseUpvalue);
+            } else {
+                self.emit_opcode(OpCode::Pop);
+            }
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        /*
+            condition
+            OP_JUMP_IF_FALSE  --+
+            OP_POP              |   <- pop the condition
+            then branch         |
+            OP_JUMP  ---------- | --+
+            OP_POP  <-----------+   |   <- pop the condition
+            else branch             |
+            ...  <------------------+
+        */
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_opcode(OpCode::Pop);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(then_jump);
+        self.emit_opcode(OpCode::Pop);
+
+        if self.match_token(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().count();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_opcode(OpCode::Pop);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_opcode(OpCode::Pop);
+    }
+
+    fn for_statement(&mut self) {
+        // A variable declared in the initializer is scoped to the loop.
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        // `for (x in xs)` and `for (var x in xs)` both iterate, the rest is a C-style loop.
+        let has_var = self.match_token(TokenType::Var);
+        let next = self.scanner.peek_token();
+        if self.check(TokenType::Identifier) && next.token_type == TokenType::Identifier && next.lexeme == "in" {
+            self.for_in_statement();
+            self.end_scope();
+            return;
+        }
+
+        if has_var {
+            self.var_declaration();
+        } else if self.match_token(TokenType::Semicolon) {
+            // No initializer.
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().count();
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_opcode(OpCode::Pop);
+        }
+
+        if !self.match_token(TokenType::RightParen) {
+            /*
+                The increment is written before the body but has to run after it,
+                so we jump over it, run the body, then loop back to it.
+
+                condition -> OP_JUMP_IF_FALSE (exit)
+                OP_JUMP (body) ------+
+                increment  <---------|---+
+                OP_LOOP (condition)  |   |
+                body  <--------------+   |
+                OP_LOOP (increment) -----+
+            */
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().count();
+            self.expression();
+            self.emit_opcode(OpCode::Pop);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_opcode(OpCode::Pop);
+        }
+        self.end_scope();
+    }
+
+    // The iterator lives in a hidden local, and every iteration gets a fresh loop variable
+    // so closures created in the body each capture their own item.
+    /*
+        iterable
+        OP_GET_ITER             (iterator)
+        OP_ITER_NEXT  <------+  (iterator) (item or done)
+        OP_ITER_CHECK (exit) |  skipped by OP_ITER_NEXT unless the iterator is a user object
+        body                 |  (iterator) (x)
+        OP_POP               |
+        OP_LOOP -------------+
+        exit:                   OP_ITER_CHECK popped the done
+    */
+    fn for_in_statement(&mut self) {
+        self.consume(TokenType::Identifier, "Expect loop variable name.");
+        let name = self.parser.previous.clone();
+        self.consume_contextual("in", "Expect 'in' after loop variable.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+        self.emit_opcode(OpCode::GetIter);
+        self.add_local(Self::synthetic_token("(iterator)"));
+        self.mark_initialized();
+
+        let loop_start = self.current_chunk().count();
+        self.emit_opcode(OpCode::IterNext);
+        let exit_jump = self.emit_jump(OpCode::IterCheck);
+
+        self.begin_scope();
+        self.add_local(name);
+        self.mark_initialized();
+        self.statement();
+        self.end_scope();
+
+        self.emit_loop(loop_start);
+        self.patch_jump(exit_jump);
+    }
+
+    fn return_statement(&mut self) {
+        if self.current().function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.current().function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+            // next() gives `done` once a generator has returned, there is nowhere for a value to go.
+            if self.current().function_type == FunctionType::Generator {
+                self.error("Can't return a value from a generator.");
+            }
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_opcode(OpCode::Return);
+        }
+    }
+
+    // match (value) { pattern => statement, ... }
+    // The arms are compiled first and the code choosing one of them last, once every pattern is known:
+    /*
+        value                                  hidden local (match)
+        OP_JUMP (dispatch)
+        arm: bindings, body, OP_JUMP (end)  <-----+
+        ...                                       |
+        no match: OP_NO_MATCH   (without `_`) <---+
+        dispatch: tests and OP_LOOPs, or one OP_JUMP_TABLE
+        end:
+    */
+    fn match_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'match'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after match value.");
+        self.add_local(Self::synthetic_token("(match)"));
+        self.mark_initialized();
+        let slot = (self.current().locals.len() - 1) as u8;
+        self.consume(TokenType::LeftBrace, "Expect '{' before match arms.");
+
+        let dispatch_jump = self.emit_jump(OpCode::Jump);
+        let mut arms = Vec::new();
+        let mut end_jumps = Vec::new();
+        let mut matched_literals: Vec<Value> = Vec::new();
+        let mut has_catch_all = false;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            let start = self.parser.current.clone();
+            let pattern = self.pattern();
+            self.consume(TokenType::Arrow, "Expect '=>' after pattern.");
+
+            // Nothing gets past a catch-all, and a literal can only be matched once.
+            let literals = pattern.literals();
+            let already_matched = literals
+                .as_ref()
+                .is_some_and(|literals| literals.iter().all(|literal| matched_literals.contains(literal)));
+            if has_catch_all || already_matched {
+                self.warning_at(&start, "Unreachable match arm.");
+            }
+            matched_literals.extend(literals.unwrap_or_default());
+            has_catch_all |= pattern.is_irrefutable();
+
+            let body = self.current_chunk().count();
+            self.begin_scope();
+            let mut bindings = Vec::new();
+            pattern.bindings(&mut Vec::new(), &mut bindings);
+            for (index, (name, path)) in bindings.iter().enumerate() {
+                if bindings[..index].iter().any(|(other, _)| other.lexeme == name.lexeme) {
+                    self.error_at(name, "Already a variable with this name in this pattern.");
+                }
+                self.load_match_value(slot, path);
+                self.add_local(name.clone());
+                self.mark_initialized();
+            }
+            self.statement();
+            self.end_scope();
+            end_jumps.push(self.emit_jump(OpCode::Jump));
+            arms.push(MatchArm { pattern, body });
+            self.match_token(TokenType::Comma);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after match arms.");
+
+        let no_match = if has_catch_all {
+            None
+        } else {
+            let no_match = self.current_chunk().count();
+            self.emit_opcode(OpCode::GetLocal);
+            self.emit_byte(slot);
+            self.emit_opcode(OpCode::NoMatch);
+            Some(no_match)
+        };
+
+        self.patch_jump(dispatch_jump);
+        if !self.emit_jump_table(slot, &arms, no_match) {
+            self.emit_decision_sequence(slot, &arms, no_match);
+        }
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    // pattern ('|' pattern)*
+    fn pattern(&mut self) -> Pattern {
+        let first = self.single_pattern();
+        if !self.check(TokenType::Pipe) {
+            return first;
+        }
+        let mut alternatives = vec![first];
+        while self.match_token(TokenType::Pipe) {
+            alternatives.push(self.single_pattern());
+        }
+        // Which alternative matched is only known at runtime, so the variables might not exist.
+        if alternatives.iter().any(Pattern::binds) {
+            self.error("Can't bind variables in alternative patterns.");
+        }
+        Pattern::Or(alternatives)
+    }
+
+    fn single_pattern(&mut self) -> Pattern {
+        if self.match_token(TokenType::Identifier) {
+            let name = self.parser.previous.clone();
+            return if name.lexeme == "_" { Pattern::Wildcard } else { Pattern::Binding(name) };
+        }
+        if self.match_token(TokenType::LeftBracket) {
+            let mut items = Vec::new();
+            while !self.check(TokenType::RightBracket) {
+                items.push(self.pattern());
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+            self.consume(TokenType::RightBracket, "Expect ']' after list pattern.");
+            if items.len() > u8::MAX as usize {
+                self.error("Can't have more than 255 items in a list pattern.");
+            }
+            return Pattern::List(items);
+        }
+
+        let low = self.literal_pattern();
+        if self.match_token(TokenType::DotDot) {
+            let high = self.literal_pattern();
+            if low.as_number().is_none() || high.as_number().is_none() {
+                self.error("Range patterns need a number at both ends.");
+            }
+            return Pattern::Range(low, high);
+        }
+        Pattern::Literal(low)
+    }
+
+    fn literal_pattern(&mut self) -> Value {
+        let negative = self.match_token(TokenType::Minus);
+        self.advance();
+        let value = match self.parser.previous.token_type {
+            TokenType::Number => self.number_value(),
+            TokenType::String if !negative => {
+                Value::Obj(self.vm.alloc_string(self.parser.previous.lexeme.clone()))
+            }
+            TokenType::True if !negative => Value::Bool(true),
+            TokenType::False if !negative => Value::Bool(false),
+            TokenType::Nil if !negative => Value::Nil,
+            _ => {
+                self.error("Expect pattern.");
+                return Value::Nil;
+            }
+        };
+        let value = if negative {
+            match number::negate(value).expect("Only numbers can be negative") {
+                Computed::Value(value) => value,
+                Computed::Big(big) => self.vm.big_int(big),
+            }
+        } else {
+            value
+        };
+        // The pattern only becomes a constant once the arms are compiled.
+        self.vm.add_compiler_root(value);
+        value
+    }
+
+    // Push the part of the matched value a pattern looks at, e.g. value[1][0] for the b in [_, [b]].
+    fn load_match_value(&mut self, slot: u8, path: &[usize]) {
+        self.emit_opcode(OpCode::GetLocal);
+        self.emit_byte(slot);
+        for &index in path {
+            self.emit_constant(Value::Int(index as i64));
+            self.emit_opcode(OpCode::IndexGet);
+        }
+    }
+
+    // Try the arms one after the other, jumping back to the first that matches.
+    fn emit_decision_sequence(&mut self, slot: u8, arms: &[MatchArm], no_match: Option<usize>) {
+        for arm in arms {
+            let failures = self.emit_pattern_test(slot, &arm.pattern, &mut Vec::new());
+            self.emit_loop(arm.body);
+            if failures.is_empty() {
+                // A catch-all: the arms after it can't be reached.
+                return;
+            }
+            for jump in failures {
+                self.patch_jump(jump);
+            }
+            self.emit_opcode(OpCode::Pop);
+        }
+        self.emit_loop(no_match.expect("Matches without a catch-all arm have a no match block"));
+    }
+
+    // Code that falls through when the pattern matches, and otherwise takes one of
+    // the returned jumps with a false left on the stack.
+    fn emit_pattern_test(&mut self, slot: u8, pattern: &Pattern, path: &mut Vec<usize>) -> Vec<usize> {
+        match pattern {
+            // Nothing to test, even when some alternatives before the catch-all could be tested.
+            pattern if pattern.is_irrefutable() => Vec::new(),
+            Pattern::Wildcard | Pattern::Binding(_) => unreachable!("Irrefutable patterns are handled above"),
+            Pattern::Literal(value) => {
+                self.load_match_value(slot, path);
+                self.emit_constant(*value);
+                self.emit_opcode(OpCode::Equal);
+                self.emit_match_check()
+            }
+            Pattern::Range(low, high) => {
+                self.load_match_value(slot, path);
+                self.emit_constant(*low);
+                self.emit_constant(*high);
+                self.emit_opcode(OpCode::MatchRange);
+                self.emit_match_check()
+            }
+            Pattern::List(items) => {
+                // The length is checked first, so indexing the items afterwards is safe.
+                self.load_match_value(slot, path);
+                self.emit_opcode(OpCode::MatchLen);
+                self.emit_byte(items.len() as u8);
+                let mut failures = self.emit_match_check();
+                for (index, item) in items.iter().enumerate() {
+                    path.push(index);
+                    failures.extend(self.emit_pattern_test(slot, item, path));
+                    path.pop();
+                }
+                failures
+            }
+            Pattern::Or(alternatives) => {
+                let (last, others) = alternatives.split_last().expect("An or pattern has alternatives");
+                let mut matched = Vec::new();
+                for alternative in others {
+                    let failures = self.emit_pattern_test(slot, alternative, path);
+                    matched.push(self.emit_jump(OpCode::Jump));
+                    for jump in failures {
+                        self.patch_jump(jump);
+                    }
+                    self.emit_opcode(OpCode::Pop);
+                }
+                let failures = self.emit_pattern_test(slot, last, path);
+                for jump in matched {
+                    self.patch_jump(jump);
+                }
+                failures
+            }
+        }
+    }
+
+    fn emit_match_check(&mut self) -> Vec<usize> {
+        let failure = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_opcode(OpCode::Pop);
+        vec![failure]
+    }
+
+    // When every arm (but a last catch-all) matches ints, and they are close to each other,
+    // one instruction picks the arm instead of testing them one by one:
+    /*
+        match (x) { 1 => a, 2 | 3 => b, 5 => c, _ => d }
+
+        OP_GET_LOCAL (match)
+        OP_JUMP_TABLE min 1 len 5
+            default -> d
+            1 -> a, 2 -> b, 3 -> b, 4 -> d, 5 -> c
+    */
+    // Every arm comes before the table, so the entries are backward offsets from the end of it.
+    // Returns false when the arms are not a good fit.
+    fn emit_jump_table(&mut self, slot: u8, arms: &[MatchArm], no_match: Option<usize>) -> bool {
+        let mut cases: Vec<(i64, usize)> = Vec::new();
+        let mut default = no_match;
+        for arm in arms {
+            if arm.pattern.is_irrefutable() {
+                default = Some(arm.body);
+                break;
+            }
+            let Some(literals) = arm.pattern.literals() else {
+                return false;
+            };
+            for literal in literals {
+                let Value::Int(value) = literal else {
+                    return false;
+                };
+                // An earlier arm wins, like it would with tests.
+                if !cases.iter().any(|&(case, _)| case == value) {
+                    cases.push((value, arm.body));
+                }
+            }
+        }
+
+        let (Some(min), Some(max)) = (cases.iter().map(|case| case.0).min(), cases.iter().map(|case| case.0).max()) else {
+            return false;
+        };
+        let span = max as i128 - min as i128 + 1;
+        if cases.len() < JUMP_TABLE_MIN_CASES || span > u8::MAX as i128 || span > 2 * cases.len() as i128 {
+            return false;
+        }
+        let default = default.expect("Matches without a catch-all arm have a no match block");
+
+        self.emit_opcode(OpCode::GetLocal);
+        self.emit_byte(slot);
+        let min_constant = self.make_constant(Value::Int(min));
+        self.emit_opcode(OpCode::JumpTable);
+        self.emit_byte(min_constant);
+        self.emit_byte(span as u8);
+        let end = self.current_chunk().count() + 2 * (span as usize + 1);
+        let targets = iter::once(default).chain((min..=max).map(|value| {
+            cases.iter().find(|&&(case, _)| case == value).map_or(default, |&(_, body)| body)
+        }));
+        for target in targets.collect::<Vec<_>>() {
+            let offset = end - target;
+            if offset > u16::MAX as usize {
+                self.error("Too much code to jump over.");
+            }
+            let [high, low] = (offset as u16).to_be_bytes();
+            self.emit_byte(high);
+            self.emit_byte(low);
+        }
+        true
+    }
+
+    // `yield;` is `yield nil;`.
+    fn yield_statement(&mut self) {
+        if self.current().function_type != FunctionType::Generator {
+            self.error("Can't yield outside of a generator.");
+        }
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_opcode(OpCode::Nil);
+        } else {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after yielded value.");
+        }
+        self.emit_opcode(OpCode::Yield);
     }
 
-    fn error(&mut self, message: &str) {
-        self.error_at(&self.parser.previous.clone(), message);
+    fn throw_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.");
+        self.emit_opcode(OpCode::Throw);
     }
 
-    fn error_at(&mut self, token: &Token, message: &str) {
-        if self.parser.panic_mode {
-            return;
-        }
-        self.parser.panic_mode = true;
-        eprint!("[line {}] Error", token.line);
+    // The blocks are laid out one after the other, the handler table tells the VM where to go on a throw:
+    /*
+        try_start:   try block
+        try_end:     OP_JUMP (finally)              <- only with a catch
+        catch:       catch block, `e` in slot depth    (handler for [try_start, try_end))
+                     OP_NIL, COMPLETION_NORMAL      <- only with a finally
+        finally:     finally block                     (handler for [try_start, finally))
+                     OP_END_FINALLY
+    */
+    fn try_statement(&mut self) {
+        let stack_depth = self.current().locals.len();
+        let try_start = self.current_chunk().count();
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.");
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        let try_end = self.current_chunk().count();
 
-        if token.token_type == TokenType::Eof {
-            eprint!(" at end");
-        } else if token.token_type == TokenType::Error {
-            // Nothing
-        } else {
-            eprint!(" at '{}'", token.lexeme);
+        let has_catch = self.match_token(TokenType::Catch);
+        if has_catch {
+            let skip_catch = self.emit_jump(OpCode::Jump);
+            let catch_start = self.current_chunk().count();
+
+            // The VM pushes the exception right where the catch variable's slot is.
+            self.begin_scope();
+            self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.");
+            self.consume(TokenType::Identifier, "Expect exception variable name.");
+            self.declare_variable();
+            self.mark_initialized();
+            self.consume(TokenType::RightParen, "Expect ')' after exception variable.");
+            self.consume(TokenType::LeftBrace, "Expect '{' before catch body.");
+            self.block();
+            self.end_scope();
+
+            self.patch_jump(skip_catch);
+            self.current_chunk().add_handler(ExceptionHandler {
+                start: try_start,
+                end: try_end,
+                target: catch_start,
+                stack_depth,
+                is_finally: false,
+            });
         }
 
-        eprintln!(": {}", message);
-        self.parser.had_error = true;
-    }
+        if self.match_token(TokenType::Finally) {
+            // Reaching the finally block normally: no value, nothing to resume afterwards.
+            self.emit_opcode(OpCode::Nil);
+            self.emit_constant(Value::Number(COMPLETION_NORMAL));
+            let finally_start = self.current_chunk().count();
 
-    fn consume(&mut self, token_type: TokenType, message: &str) {
-        // Consume a token of the expected type, or report an error
-        if self.parser.current.token_type == token_type {
-            self.advance();
-            return;
+            self.begin_scope();
+            // Names that can't be written in Lox, so the block can't touch these slots.
+            self.add_local(Self::synthetic_token("(finally value)"));
+            self.mark_initialized();
+            self.add_local(Self::synthetic_token("(finally completion)"));
+            self.mark_initialized();
+            self.consume(TokenType::LeftBrace, "Expect '{' after 'finally'.");
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+            // Either resumes a return or exception, or falls through to pop the two hidden locals.
+            self.emit_opcode(OpCode::EndFinally);
+            self.end_scope();
+
+            self.current_chunk().add_handler(ExceptionHandler {
+                start: try_start,
+                end: finally_start,
+                target: finally_start,
+                stack_depth,
+                is_finally: true,
+            });
+        } else if !has_catch {
+            self.error("Expect 'catch' or 'finally' after try block.");
         }
-        self.error_at_current(message);
     }
 
-    fn emit_byte(&mut self, byte: u8) {
-        let line = self.parser.previous.line;
-        self.current_chunk().write(byte, line);
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_opcode(OpCode::Print);
     }
 
-    fn emit_opcode(&mut self, opcode: OpCode) {
-        let line = self.parser.previous.line;
-        self.current_chunk().write_opcode(opcode, line);
+    fn expression_statement(&mut self) {
+        // Evaluate the expression for its side effect, then throw the result away.
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_opcode(OpCode::Pop);
     }
 
-    fn emit_constant(&mut self, value: Value) {
-        let line = self.parser.previous.line;
-        self.current_chunk().write_constant(value, line);
-    }
+    fn synchronize(&mut self) {
+        self.parser.panic_mode = false;
 
-    fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment);
+        while self.parser.current.token_type != TokenType::Eof {
+            if self.parser.previous.token_type == TokenType::Semicolon {
+                return;
+            }
+            match self.parser.current.token_type {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::Const
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return
+                | TokenType::Throw
+                | TokenType::Yield
+                | TokenType::Match
+                | TokenType::Try
+                | TokenType::Import
+                | TokenType::Export => return,
+                _ => {}
+            }
+            self.advance();
+        }
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
@@ -168,9 +1623,12 @@ impl Compiler {
         self.advance();
         // 2. Call the prefix function
         let prefix_rule = Self::get_rule(self.parser.previous.token_type).prefix;
-        
+
+        // `a * b = c` must not be parsed as `a * (b = c)`, so assignment is only
+        // allowed when we are parsing at the lowest precedence.
+        let can_assign = precedence <= Precedence::Assignment;
         match prefix_rule {
-            Some(prefix_fn) => prefix_fn(self),
+            Some(prefix_fn) => prefix_fn(self, can_assign),
             None => {
                 self.error("Expect expression.");
                 return;
@@ -182,12 +1640,17 @@ impl Compiler {
             self.advance();
             let infix_rule = Self::get_rule(self.parser.previous.token_type).infix;
             if let Some(infix_fn) = infix_rule {
-                infix_fn(self);
+                infix_fn(self, can_assign);
             }
         }
+
+        // Nobody consumed the `=`, so the left-hand side was not something we can assign to.
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.error("Invalid assignment target.");
+        }
     }
 
-    fn literal(&mut self) {
+    fn literal(&mut self, _can_assign: bool) {
         match self.parser.previous.token_type {
             TokenType::False => self.emit_opcode(OpCode::False),
             TokenType::True => self.emit_opcode(OpCode::True),
@@ -196,15 +1659,215 @@ impl Compiler {
         }
     }
 
-    fn number(&mut self) {
-        let value: f64 = self.parser.previous.lexeme.parse()
-            .expect("Failed to parse number");
+    fn number(&mut self, _can_assign: bool) {
+        let value = self.number_value();
+        self.emit_constant(value);
+    }
+
+    fn number_value(&mut self) -> Value {
         // we use .previous because parse_precedence has already advanced past the number token, 
         // moving it from current to previous before calling our parsing function.
-        self.emit_constant(Value::Number(value));
+        let lexeme = &self.parser.previous.lexeme;
+        // 3 is an int, 3.0 is a float.
+        if lexeme.contains('.') {
+            Value::Number(lexeme.parse().expect("The scanner only produces valid numbers"))
+        } else {
+            match lexeme.parse() {
+                Ok(value) => Value::Int(value),
+                // Too big for 64 bits, so it becomes a bignum constant.
+                Err(_) => {
+                    let value = BigInt::parse(lexeme).expect("The scanner only produces digits");
+                    self.vm.big_int(value)
+                }
+            }
+        }
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        // The scanner already dropped the quotes and replaced the escapes.
+        let chars = self.parser.previous.lexeme.clone();
+        let string = self.vm.alloc_string(chars);
+        self.emit_constant(Value::Obj(string));
+    }
+
+    // "a${x}b${y}c" compiles like "a" + str(x) + "b" + str(y) + "c", skipping the empty pieces.
+    fn interpolation(&mut self, _can_assign: bool) {
+        // The first piece is always a string, either the fragment or the first stringified expression.
+        let has_prefix = !self.parser.previous.lexeme.is_empty();
+        if has_prefix {
+            self.string(false);
+        }
+
+        let mut is_first_part = !has_prefix;
+        loop {
+            self.expression();
+            self.emit_opcode(OpCode::ToString);
+            if !is_first_part {
+                self.emit_opcode(OpCode::Add);
+            }
+            is_first_part = false;
+
+            let is_last_fragment = !self.match_token(TokenType::Interpolation);
+            if is_last_fragment {
+                self.consume(TokenType::String, "Expect end of string interpolation.");
+            }
+            if !self.parser.previous.lexeme.is_empty() {
+                self.string(false);
+                self.emit_opcode(OpCode::Add);
+            }
+            if is_last_fragment {
+                break;
+            }
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.parser.previous.clone();
+        self.named_variable(&name, can_assign);
+    }
+
+    fn named_variable(&mut self, name: &Token, can_assign: bool) {
+        if let Some(inline_value) = self.const_variable(name) {
+            if can_assign && self.check(TokenType::Equal) {
+                self.error_at(name, &format!("Can't assign to constant '{}'.", name.lexeme));
+            } else if let Some(value) = inline_value {
+                // Its value is known and never changes, no need to look it up.
+                self.callee = None;
+                self.emit_constant(value);
+                return;
+            }
+        }
+
+        // Locals win over captured variables, which win over globals with the same name.
+        let current = self.function_compilers.len() - 1;
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(current, name) {
+            let function = self.current().locals[slot as usize].function;
+            self.callee = function.filter(|_| self.check(TokenType::LeftParen)).map(|function| (slot, function));
+            (OpCode::GetLocal, OpCode::SetLocal, slot)
+        } else if let Some(index) = self.resolve_upvalue(current, name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, index)
+        } else {
+            let arg = self.identifier_constant(name);
+            (OpCode::GetGlobal, OpCode::SetGlobal, arg)
+        };
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.forget_function(name);
+            self.expression();
+            self.emit_opcode(set_op);
+        } else {
+            self.emit_opcode(get_op);
+        }
+        self.emit_byte(arg);
+    }
+
+    // Some(value to inline, if any) when the variable `name` refers to is a const.
+    fn const_variable(&self, name: &Token) -> Option<Option<Value>> {
+        // The innermost local with that name, in this function or one around it, is the one it refers to.
+        for compiler in self.function_compilers.iter().rev() {
+            if let Some(local) = compiler.locals.iter().rev().find(|local| local.name.lexeme == name.lexeme) {
+                return local.is_const.then_some(local.inline_value);
+            }
+        }
+        self.global_consts.get(&name.lexeme).copied()
+    }
+
+    // After `name = value`, the local might not hold the function its `fun` declaration gave it anymore.
+    // A call with named arguments compiled before could run after the assignment (in a loop), so that is an error.
+    fn forget_function(&mut self, name: &Token) {
+        let mut called_by_name = false;
+        for compiler in self.function_compilers.iter_mut().rev() {
+            if let Some(local) = compiler.locals.iter_mut().rev().find(|local| local.name.lexeme == name.lexeme) {
+                called_by_name = local.called_by_name;
+                local.function = None;
+                break;
+            }
+        }
+        if called_by_name {
+            self.error_at(name, &format!("Can't assign to '{}' after calling it with named arguments.", name.lexeme));
+        }
     }
 
-    fn unary(&mut self) {
+    // `compiler` is an index into function_compilers, so that we can also look into enclosing functions.
+    fn resolve_local(&mut self, compiler: usize, name: &Token) -> Option<u8> {
+        // Walk backwards so that the innermost (most recently declared) variable is found first.
+        let (slot, local) = self.function_compilers[compiler]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.lexeme == name.lexeme)?;
+
+        if local.depth.is_none() {
+            // var a = a; -> the `a` on the right is the one being declared.
+            self.error("Can't read local variable in its own initializer.");
+        }
+        Some(slot as u8)
+    }
+
+    fn resolve_upvalue(&mut self, compiler: usize, name: &Token) -> Option<u8> {
+        // The script is the outermost function, anything not found by then is a global.
+        if compiler == 0 {
+            return None;
+        }
+
+        /*
+            fun outer() {
+                var x = 1;
+                fun middle() {
+                    fun inner() { print x; }  <- inner captures middle's upvalue,
+                }                                which captures outer's local
+            }
+        */
+        let enclosing = compiler - 1;
+        if let Some(slot) = self.resolve_local(enclosing, name) {
+            self.function_compilers[enclosing].locals[slot as usize].is_captured = true;
+            return Some(self.add_upvalue(compiler, slot, true));
+        }
+        if let Some(index) = self.resolve_upvalue(enclosing, name) {
+            return Some(self.add_upvalue(compiler, index, false));
+        }
+        None
+    }
+
+    fn add_upvalue(&mut self, compiler: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        // A closure that mentions the same variable twice only captures it once.
+        if let Some(existing) = self.function_compilers[compiler].upvalues.iter().position(|u| *u == upvalue) {
+            return existing as u8;
+        }
+
+        if self.function_compilers[compiler].upvalues.len() == UINT8_COUNT {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+        self.function_compilers[compiler].upvalues.push(upvalue);
+        (self.function_compilers[compiler].upvalues.len() - 1) as u8
+    }
+
+    fn and(&mut self, _can_assign: bool) {
+        // If the left operand is falsy, it is the result, so skip the right operand.
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_opcode(OpCode::Pop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self, _can_assign: bool) {
+        // If the left operand is truthy, it is the result, so skip the right operand.
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_opcode(OpCode::Pop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
         let operator_type = self.parser.previous.token_type;
 
         // Compile the operand
@@ -214,11 +1877,12 @@ impl Compiler {
         match operator_type {
             TokenType::Minus => self.emit_opcode(OpCode::Negate),
             TokenType::Bang => self.emit_opcode(OpCode::Not),
+            TokenType::Tilde => self.emit_opcode(OpCode::BitNot),
             _ => unreachable!(),
         }
     }
 
-    fn binary(&mut self) {
+    fn binary(&mut self, _can_assign: bool) {
         // Parse binary expressions: left + right, left * right, etc.
         let operator_type = self.parser.previous.token_type;
         let rule = Self::get_rule(operator_type);
@@ -234,6 +1898,12 @@ impl Compiler {
             TokenType::Minus => self.emit_opcode(OpCode::Subtract),
             TokenType::Star => self.emit_opcode(OpCode::Multiply),
             TokenType::Slash => self.emit_opcode(OpCode::Divide),
+            TokenType::Percent => self.emit_opcode(OpCode::Modulo),
+            TokenType::Ampersand => self.emit_opcode(OpCode::BitAnd),
+            TokenType::Pipe => self.emit_opcode(OpCode::BitOr),
+            TokenType::Caret => self.emit_opcode(OpCode::BitXor),
+            TokenType::LessLess => self.emit_opcode(OpCode::ShiftLeft),
+            TokenType::GreaterGreater => self.emit_opcode(OpCode::ShiftRight),
             TokenType::EqualEqual => self.emit_opcode(OpCode::Equal),
             TokenType::Greater => self.emit_opcode(OpCode::Greater),
             TokenType::Less => self.emit_opcode(OpCode::Less),
@@ -254,7 +1924,316 @@ impl Compiler {
         }
     }
 
-    fn grouping(&mut self) {
+    fn call(&mut self, _can_assign: bool) {
+        let callee = self.callee.take();
+        // The callee is already on the stack, the arguments go right above it.
+        let (arg_count, names) = self.argument_list();
+        if names.is_empty() {
+            self.emit_opcode(OpCode::Call);
+            self.emit_byte(arg_count);
+            return;
+        }
+
+        // Calling a local function we saw the declaration of: its parameters are known, so the names
+        // are matched right now and the VM only has to move the arguments into place:
+        /*
+            fun f(a, b = 2, c = 3) {}
+            f(c: 3, a: 1);          OP_SHUFFLE (2 args) 1 - 0, OP_CALL 3
+        */
+        // When they already are in the order of the parameters it is a plain call.
+        if let Some((slot, function)) = callee {
+            self.current_mut().locals[slot as usize].called_by_name = true;
+            if let Some(order) = self.named_argument_order(function, arg_count, &names) {
+                if order.iter().enumerate().any(|(parameter, &argument)| argument != parameter as u8) {
+                    self.emit_opcode(OpCode::Shuffle);
+                    self.emit_byte(arg_count);
+                    self.emit_byte(order.len() as u8);
+                    for argument in &order {
+                        self.emit_byte(*argument);
+                    }
+                }
+                self.emit_opcode(OpCode::Call);
+                self.emit_byte(order.len() as u8);
+                return;
+            }
+        }
+
+        // Otherwise the VM finds out which parameter each name is, see VM::match_named_arguments.
+        let name_constants: Vec<u8> = names.iter().map(|name| self.identifier_constant(name)).collect();
+        self.emit_opcode(OpCode::CallNamed);
+        self.emit_byte(arg_count);
+        self.emit_byte(name_constants.len() as u8);
+        for constant in name_constants {
+            self.emit_byte(constant);
+        }
+    }
+
+    // Report the mistakes the VM would report for these named arguments, or else say which argument each
+    // parameter gets: its index among the arguments, NOT_GIVEN for one left out before the last one given.
+    fn named_argument_order(&mut self, function: ObjRef, arg_count: u8, names: &[Token]) -> Option<Vec<u8>> {
+        let function = function.as_function().expect("Local functions are compiled functions");
+        let parameters: Vec<String> = function.parameters[..function.arity]
+            .iter()
+            .map(|parameter| parameter.as_string().expect("Parameter names are strings").chars.clone())
+            .collect();
+        let required = function.arity - function.optional;
+        let positional = arg_count as usize - names.len();
+
+        let mut order: Vec<Option<u8>> = (0..positional as u8).map(Some).collect();
+        order.resize(positional.max(parameters.len()), None);
+        for (i, name) in names.iter().enumerate() {
+            match parameters.iter().position(|parameter| *parameter == name.lexeme) {
+                None => {
+                    self.error_at(name, &format!("No parameter named '{}'.", name.lexeme));
+                    return None;
+                }
+                Some(index) if order[index].is_some() => {
+                    self.error_at(name, &format!("Argument for parameter '{}' given twice.", name.lexeme));
+                    return None;
+                }
+                Some(index) => order[index] = Some((positional + i) as u8),
+            }
+        }
+        if let Some(missing) = (0..required).find(|&index| order[index].is_none()) {
+            self.error(&format!("Missing argument for parameter '{}'.", parameters[missing]));
+            return None;
+        }
+        // The call itself fills in the parameters after the last one given.
+        while order.last() == Some(&None) {
+            order.pop();
+        }
+        Some(order.into_iter().map(|argument| argument.unwrap_or(NOT_GIVEN)).collect())
+    }
+
+    // The arguments and the names of the named ones, which come after the others: f(1, 2, c: 3, d: 4)
+    fn argument_list(&mut self) -> (u8, Vec<Token>) {
+        let mut arg_count: usize = 0;
+        let mut names = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if self.check(TokenType::Identifier) && self.scanner.peek_token().token_type == TokenType::Colon {
+                    self.advance();
+                    names.push(self.parser.previous.clone());
+                    self.advance();
+                } else if !names.is_empty() {
+                    self.error_at_current("Positional arguments can't follow named arguments.");
+                }
+                self.expression();
+                if arg_count == 255 {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        (arg_count as u8, names)
+    }
+
+    // Whether the argument list starting at the current '(' has named arguments.
+    // Method calls need to know before compiling the arguments, see dot().
+    fn named_arguments_follow(&mut self) -> bool {
+        let checkpoint = self.scanner.checkpoint();
+        let mut depth = 1;
+        let mut previous = TokenType::LeftParen;
+        let mut named = false;
+        while depth > 0 && !named {
+            let token_type = self.scanner.scan_token().token_type;
+            match token_type {
+                TokenType::LeftParen | TokenType::LeftBracket | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParen | TokenType::RightBracket | TokenType::RightBrace => depth -= 1,
+                // `{a: 1}` is a map, only a name right inside the parentheses is an argument name.
+                TokenType::Colon => named = depth == 1 && previous == TokenType::Identifier,
+                TokenType::Eof => break,
+                _ => {}
+            }
+            previous = token_type;
+        }
+        self.scanner.restore(checkpoint);
+        named
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.parser.previous.clone();
+        let name_constant = self.identifier_constant(&name);
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_opcode(OpCode::SetProperty);
+            self.emit_byte(name_constant);
+        } else if self.check(TokenType::LeftParen) && !self.named_arguments_follow() {
+            self.advance();
+            // instance.method(args): call the method directly without creating a bound method.
+            let (arg_count, _) = self.argument_list();
+            self.emit_opcode(OpCode::Invoke);
+            self.emit_byte(name_constant);
+            self.emit_byte(arg_count);
+        } else {
+            // With named arguments, the method is bound first and then called like any function.
+            self.callee = None;
+            self.emit_opcode(OpCode::GetProperty);
+            self.emit_byte(name_constant);
+        }
+    }
+
+    // [a, b, c]: push every item, then OP_BUILD_LIST collects them into a new list.
+    fn list(&mut self, _can_assign: bool) {
+        let mut item_count: usize = 0;
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                // Allow a trailing comma: [1, 2, 3,]
+                if self.check(TokenType::RightBracket) {
+                    break;
+                }
+                self.expression();
+                if item_count == 255 {
+                    self.error("Can't have more than 255 items in a list literal.");
+                }
+                item_count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list items.");
+        self.emit_opcode(OpCode::BuildList);
+        self.emit_byte(item_count as u8);
+    }
+
+    // {key: value, ...}: push every key and value, then OP_BUILD_MAP collects them into a new map.
+    // A `{` at the start of a statement is still a block, maps only appear where an expression is expected.
+    fn map(&mut self, _can_assign: bool) {
+        let mut entry_count: usize = 0;
+        if !self.check(TokenType::RightBrace) {
+            loop {
+                if self.check(TokenType::RightBrace) {
+                    break;
+                }
+                self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after map key.");
+                self.expression();
+                if entry_count == 255 {
+                    self.error("Can't have more than 255 entries in a map literal.");
+                }
+                entry_count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries.");
+        self.emit_opcode(OpCode::BuildMap);
+        self.emit_byte(entry_count as u8);
+    }
+
+    // list[index] or list[index] = value (same for maps), the list is already on the stack.
+    fn subscript(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_opcode(OpCode::IndexSet);
+        } else {
+            self.emit_opcode(OpCode::IndexGet);
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.class_compilers.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        // `this` is just a local variable in slot 0 that cannot be assigned to.
+        self.variable(false);
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        match self.class_compilers.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.");
+            }
+            Some(_) => {}
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.parser.previous.clone();
+        let name_constant = self.identifier_constant(&name);
+
+        // Stack: [ this ][ superclass ], the VM looks the method up on the superclass and binds it to this.
+        self.named_variable(&Self::synthetic_token("this"), false);
+        if self.check(TokenType::LeftParen) && !self.named_arguments_follow() {
+            self.advance();
+            let (arg_count, _) = self.argument_list();
+            self.named_variable(&Self::synthetic_token("super"), false);
+            self.emit_opcode(OpCode::SuperInvoke);
+            self.emit_byte(name_constant);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(&Self::synthetic_token("super"), false);
+            self.emit_opcode(OpCode::GetSuper);
+            self.emit_byte(name_constant);
+        }
+    }
+
+    // An anonymous function: `fun (a, b) { return a + b; }`, or `fun* () { ... }` for a generator.
+    fn lambda(&mut self, _can_assign: bool) {
+        let function_type = if self.match_token(TokenType::Star) {
+            FunctionType::Generator
+        } else {
+            FunctionType::Function
+        };
+        self.function(function_type, LAMBDA_NAME);
+    }
+
+    // `(a, b) => a + b` is short for `fun (a, b) { return a + b; }`.
+    // The '(' is already consumed, and the body is a single expression.
+    fn arrow_function(&mut self) {
+        self.begin_function(FunctionType::Function, LAMBDA_NAME);
+        self.parameters();
+        self.consume(TokenType::Arrow, "Expect '=>' after parameters.");
+        self.expression();
+        self.emit_opcode(OpCode::Return);
+        self.end_function();
+    }
+
+    // Whether the '(' we just consumed starts the parameters of an arrow function rather than a grouping.
+    // Both look the same until the matching ')', so we scan ahead to see whether a '=>' follows it:
+    /*
+        (a, b) => a + b      arrow function
+        (a + b) * 2          grouping
+    */
+    fn is_arrow_function(&mut self) -> bool {
+        let checkpoint = self.scanner.checkpoint();
+        let mut token_type = self.parser.current.token_type;
+        let mut depth = 1;
+        loop {
+            match token_type {
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen => depth -= 1,
+                TokenType::Eof => break,
+                _ => {}
+            }
+            if depth == 0 {
+                break;
+            }
+            token_type = self.scanner.scan_token().token_type;
+        }
+        let is_arrow = depth == 0 && self.scanner.scan_token().token_type == TokenType::Arrow;
+        self.scanner.restore(checkpoint);
+        is_arrow
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
+        if self.is_arrow_function() {
+            self.arrow_function();
+            return;
+        }
         // Parse parenthesized expressions: ( expression )
         // We assume the initial ( has already been consumed
         // because we can call this grouping after we know that we have '('
@@ -262,80 +2241,165 @@ impl Compiler {
         self.consume(TokenType::RightParen, "Expect ')' after expression.");
     }
 
-    fn get_rule(token_type: TokenType) -> &'static ParseRule {
+    fn get_rule(token_type: TokenType) -> ParseRule<'a> {
         use TokenType::*;
         match token_type {
-            LeftParen => &ParseRule { 
-                prefix: Some(Compiler::grouping), 
-                infix: None, 
-                precedence: Precedence::None 
+            LeftParen => ParseRule { 
+                prefix: Some(Compiler::grouping), // (a + b), or the parameters of (a, b) => a + b
+                infix: Some(Compiler::call), // f(a, b)
+                precedence: Precedence::Call
+            },
+            Interpolation => ParseRule {
+                prefix: Some(Compiler::interpolation),
+                infix: None,
+                precedence: Precedence::None
+            },
+            LeftBrace => ParseRule {
+                prefix: Some(Compiler::map),       // {"a": 1}
+                infix: None,
+                precedence: Precedence::None
             },
-            Number => &ParseRule { 
+            LeftBracket => ParseRule {
+                prefix: Some(Compiler::list),      // [1, 2, 3]
+                infix: Some(Compiler::subscript),  // list[0]
+                precedence: Precedence::Call
+            },
+            Number => ParseRule { 
                 prefix: Some(Compiler::number), 
                 infix: None, 
                 precedence: Precedence::None 
             },
-            Minus => &ParseRule { 
+            Minus => ParseRule { 
                 prefix: Some(Compiler::unary),  // We can see sth like -1
                 infix: Some(Compiler::binary),  // We can see sth like 1 - 5
                 precedence: Precedence::Term    // Minus has a precedence of "Term"
             },
-            Plus => &ParseRule { 
+            Plus => ParseRule { 
                 prefix: None, 
                 infix: Some(Compiler::binary), 
                 precedence: Precedence::Term    // Plus has a precedence of "Term" 
             },
-            Star => &ParseRule { 
+            Star => ParseRule { 
                 prefix: None, // We don't see *5
                 infix: Some(Compiler::binary), // We can see 5 * 9
                 precedence: Precedence::Factor  // Star has a precedence of "Factor" 
             },
-            Slash => &ParseRule { 
+            Slash => ParseRule { 
                 prefix: None, 
                 infix: Some(Compiler::binary), 
                 precedence: Precedence::Factor  // Slash has a precedence of "Factor" 
             },
-            Bang => &ParseRule {
+            Percent => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::binary),
+                precedence: Precedence::Factor
+            },
+            Ampersand => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::binary),
+                precedence: Precedence::BitAnd
+            },
+            Pipe => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::binary),
+                precedence: Precedence::BitOr
+            },
+            Caret => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::binary),
+                precedence: Precedence::BitXor
+            },
+            LessLess | GreaterGreater => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::binary),
+                precedence: Precedence::Shift
+            },
+            Tilde => ParseRule {
+                prefix: Some(Compiler::unary),
+                infix: None,
+                precedence: Precedence::None
+            },
+            Bang => ParseRule {
                 prefix: Some(Compiler::unary),
                 infix: None,
                 precedence: Precedence::None
             },
-            BangEqual => &ParseRule {
+            BangEqual => ParseRule {
                 prefix: None,
                 infix: Some(Compiler::binary),
                 precedence: Precedence::Equality
             },
-            EqualEqual => &ParseRule {
+            EqualEqual => ParseRule {
                 prefix: None,
                 infix: Some(Compiler::binary),
                 precedence: Precedence::Equality
             },
-            Greater => &ParseRule {
+            Greater => ParseRule {
                 prefix: None,
                 infix: Some(Compiler::binary),
                 precedence: Precedence::Comparison
             },
-            GreaterEqual => &ParseRule {
+            GreaterEqual => ParseRule {
                 prefix: None,
                 infix: Some(Compiler::binary),
                 precedence: Precedence::Comparison
             },
-            Less => &ParseRule {
+            Less => ParseRule {
                 prefix: None,
                 infix: Some(Compiler::binary),
                 precedence: Precedence::Comparison
             },
-            LessEqual => &ParseRule {
+            LessEqual => ParseRule {
                 prefix: None,
                 infix: Some(Compiler::binary),
                 precedence: Precedence::Comparison
             },
-            False | True | Nil => &ParseRule { 
+            Dot => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::dot), // instance.field
+                precedence: Precedence::Call
+            },
+            Super => ParseRule {
+                prefix: Some(Compiler::super_),
+                infix: None,
+                precedence: Precedence::None
+            },
+            This => ParseRule {
+                prefix: Some(Compiler::this),
+                infix: None,
+                precedence: Precedence::None
+            },
+            Identifier => ParseRule {
+                prefix: Some(Compiler::variable),
+                infix: None,
+                precedence: Precedence::None
+            },
+            String => ParseRule {
+                prefix: Some(Compiler::string),
+                infix: None,
+                precedence: Precedence::None
+            },
+            And => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::and),
+                precedence: Precedence::And
+            },
+            Or => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::or),
+                precedence: Precedence::Or
+            },
+            False | True | Nil => ParseRule { 
                 prefix: Some(Compiler::literal), 
                 infix: None, 
                 precedence: Precedence::None 
             },
-            _ => &ParseRule { 
+            Fun => ParseRule {
+                prefix: Some(Compiler::lambda),    // fun (a) { ... }
+                infix: None,
+                precedence: Precedence::None
+            },
+            _ => ParseRule { 
                 prefix: None, 
                 infix: None, 
                 precedence: Precedence::None 
@@ -344,18 +2408,67 @@ impl Compiler {
     }
 }
 
-pub fn compile(source: String) -> Option<Chunk> {
+// Compile a source file into the top-level function of `module`.
+pub fn compile(source: String, vm: &mut VM, module: ObjRef) -> Option<ObjRef> {
+    // The module is not reachable from anything yet while its code is being compiled.
+    vm.add_compiler_root(Value::Obj(module));
     let scanner = Scanner::new(source);
-    let mut compiler = Compiler::new(scanner);
+    let mut compiler = Compiler::new(scanner, vm, module);
     
     compiler.advance();
-    compiler.expression();
-    compiler.consume(TokenType::Eof, "Expect end of expression.");
-    compiler.emit_opcode(OpCode::Return);
+    while !compiler.match_token(TokenType::Eof) {
+        compiler.declaration();
+    }
+    let (function, _) = compiler.end_compiler();
 
     if compiler.parser.had_error {
         None
     } else {
-        Some(compiler.compiling_chunk)
+        Some(function)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug::disassemble_instruction;
+    use crate::object::ObjModule;
+    use std::path::PathBuf;
+
+    // The opcodes of the top-level code, decoded one instruction at a time so operands aren't mistaken for opcodes.
+    fn script_opcodes(source: &str) -> Vec<OpCode> {
+        let mut vm = VM::new();
+        let name = vm.alloc_string("test".to_string());
+        let module = vm.alloc(Obj::Module(ObjModule::new(name, PathBuf::from("."))));
+        let function = compile(source.to_string(), &mut vm, module).expect("The source compiles");
+        let chunk = &function.as_function().expect("Scripts are functions").chunk;
+        let mut opcodes = Vec::new();
+        let mut offset = 0;
+        while offset < chunk.code().len() {
+            opcodes.push(OpCode::try_from(chunk.code()[offset]).expect("Valid opcode"));
+            offset = disassemble_instruction(chunk, offset);
+        }
+        opcodes
+    }
+
+    #[test]
+    fn named_arguments_to_a_local_function_are_shuffled_while_compiling() {
+        let opcodes = script_opcodes("{ fun f(a, b) {} f(b: 1, a: 5); }");
+        assert!(opcodes.contains(&OpCode::Shuffle), "{:?}", opcodes);
+        assert!(!opcodes.contains(&OpCode::CallNamed), "{:?}", opcodes);
+    }
+
+    #[test]
+    fn named_arguments_in_parameter_order_are_a_plain_call() {
+        let opcodes = script_opcodes("{ fun f(a, b = 2) {} f(a: 1, b: 5); }");
+        assert!(!opcodes.contains(&OpCode::Shuffle), "{:?}", opcodes);
+        assert!(!opcodes.contains(&OpCode::CallNamed), "{:?}", opcodes);
+    }
+
+    #[test]
+    fn named_arguments_to_an_unknown_callee_are_matched_at_runtime() {
+        let opcodes = script_opcodes("fun f(a, b) {} f(b: 1, a: 5);");
+        assert!(opcodes.contains(&OpCode::CallNamed), "{:?}", opcodes);
+        assert!(!opcodes.contains(&OpCode::Shuffle), "{:?}", opcodes);
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,5 @@
-use crate::chunk::{Chunk};
+use crate::chunk::{Chunk, NOT_GIVEN};
+use crate::common::Value;
 use crate::value::print_value;
 use crate::opcode::OpCode;
 
@@ -9,6 +10,17 @@ pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
     while offset < chunk.count() {
         offset = disassemble_instruction(chunk, offset);
     }
+
+    for handler in chunk.handlers() {
+        println!(
+            "{} [{:04}, {:04}) -> {:04} (depth {})",
+            if handler.is_finally { "finally" } else { "catch  " },
+            handler.start,
+            handler.end,
+            handler.target,
+            handler.stack_depth
+        );
+    }
 }
 
 // Given a chunk, it will print out all of the instructions in it.
@@ -50,6 +62,57 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
             OpCode::Equal => simple_instruction("OP_EQUAL", offset),
             OpCode::Greater => simple_instruction("OP_GREATER", offset),
             OpCode::Less => simple_instruction("OP_LESS", offset),
+            OpCode::Print => simple_instruction("OP_PRINT", offset),
+            OpCode::Pop => simple_instruction("OP_POP", offset),
+            OpCode::DefineGlobal => constant_instruction("OP_DEFINE_GLOBAL", chunk, offset),
+            OpCode::GetGlobal => constant_instruction("OP_GET_GLOBAL", chunk, offset),
+            OpCode::SetGlobal => constant_instruction("OP_SET_GLOBAL", chunk, offset),
+            OpCode::GetLocal => byte_instruction("OP_GET_LOCAL", chunk, offset),
+            OpCode::SetLocal => byte_instruction("OP_SET_LOCAL", chunk, offset),
+            OpCode::Jump => jump_instruction("OP_JUMP", 1, chunk, offset),
+            OpCode::JumpIfFalse => jump_instruction("OP_JUMP_IF_FALSE", 1, chunk, offset),
+            OpCode::Loop => jump_instruction("OP_LOOP", -1, chunk, offset),
+            OpCode::Call => byte_instruction("OP_CALL", chunk, offset),
+            OpCode::GetUpvalue => byte_instruction("OP_GET_UPVALUE", chunk, offset),
+            OpCode::SetUpvalue => byte_instruction("OP_SET_UPVALUE", chunk, offset),
+            OpCode::Closure => closure_instruction(chunk, offset),
+            OpCode::CloseUpvalue => simple_instruction("OP_CLOSE_UPVALUE", offset),
+            OpCode::Class => constant_instruction("OP_CLASS", chunk, offset),
+            OpCode::GetProperty => constant_instruction("OP_GET_PROPERTY", chunk, offset),
+            OpCode::SetProperty => constant_instruction("OP_SET_PROPERTY", chunk, offset),
+            OpCode::Method => constant_instruction("OP_METHOD", chunk, offset),
+            OpCode::Invoke => invoke_instruction("OP_INVOKE", chunk, offset),
+            OpCode::Inherit => simple_instruction("OP_INHERIT", offset),
+            OpCode::GetSuper => constant_instruction("OP_GET_SUPER", chunk, offset),
+            OpCode::SuperInvoke => invoke_instruction("OP_SUPER_INVOKE", chunk, offset),
+            OpCode::BuildList => byte_instruction("OP_BUILD_LIST", chunk, offset),
+            OpCode::IndexGet => simple_instruction("OP_INDEX_GET", offset),
+            OpCode::IndexSet => simple_instruction("OP_INDEX_SET", offset),
+            OpCode::BuildMap => byte_instruction("OP_BUILD_MAP", chunk, offset),
+            OpCode::ToString => simple_instruction("OP_TO_STRING", offset),
+            OpCode::Throw => simple_instruction("OP_THROW", offset),
+            OpCode::EndFinally => simple_instruction("OP_END_FINALLY", offset),
+            OpCode::Import => constant_instruction("OP_IMPORT", chunk, offset),
+            OpCode::ImportName => constant_instruction("OP_IMPORT_NAME", chunk, offset),
+            OpCode::Modulo => simple_instruction("OP_MODULO", offset),
+            OpCode::BitAnd => simple_instruction("OP_BIT_AND", offset),
+            OpCode::BitOr => simple_instruction("OP_BIT_OR", offset),
+            OpCode::BitXor => simple_instruction("OP_BIT_XOR", offset),
+            OpCode::BitNot => simple_instruction("OP_BIT_NOT", offset),
+            OpCode::ShiftLeft => simple_instruction("OP_SHIFT_LEFT", offset),
+            OpCode::ShiftRight => simple_instruction("OP_SHIFT_RIGHT", offset),
+            OpCode::GetIter => simple_instruction("OP_GET_ITER", offset),
+            OpCode::IterNext => simple_instruction("OP_ITER_NEXT", offset),
+            OpCode::IterCheck => jump_instruction("OP_ITER_CHECK", 1, chunk, offset),
+            OpCode::Yield => simple_instruction("OP_YIELD", offset),
+            OpCode::MatchLen => byte_instruction("OP_MATCH_LEN", chunk, offset),
+            OpCode::MatchRange => simple_instruction("OP_MATCH_RANGE", offset),
+            OpCode::JumpTable => jump_table_instruction(chunk, offset),
+            OpCode::NoMatch => simple_instruction("OP_NO_MATCH", offset),
+            OpCode::CallNamed => call_named_instruction(chunk, offset),
+            OpCode::DefineConst => constant_instruction("OP_DEFINE_CONST", chunk, offset),
+            OpCode::JumpIfGiven => jump_if_given_instruction(chunk, offset),
+            OpCode::Shuffle => shuffle_instruction(chunk, offset),
         },
         Err(_) => {
             println!("Unknown opcode {}", instruction);
@@ -63,6 +126,46 @@ fn simple_instruction(name: &str, offset: usize) -> usize {
     offset + 1 // RETURN as a size of 1 byte
 }
 
+fn byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    // Local variable names are gone after compiling, so we can only show the slot number.
+    let slot = chunk.code()[offset + 1];
+    println!("{:<16} {:4}", name, slot);
+    offset + 2
+}
+
+fn jump_instruction(name: &str, sign: isize, chunk: &Chunk, offset: usize) -> usize {
+    // The operand is a 16-bit big-endian offset, measured from the end of this instruction.
+    let jump = u16::from_be_bytes([chunk.code()[offset + 1], chunk.code()[offset + 2]]) as isize;
+    let target = offset as isize + 3 + sign * jump;
+    println!("{:<16} {:4} -> {}", name, offset, target);
+    offset + 3
+}
+
+// OP_JUMP_IF_GIVEN slot, then a forward 16-bit offset measured from the end of the instruction.
+fn jump_if_given_instruction(chunk: &Chunk, offset: usize) -> usize {
+    let slot = chunk.code()[offset + 1];
+    let jump = u16::from_be_bytes([chunk.code()[offset + 2], chunk.code()[offset + 3]]) as usize;
+    println!("{:<16} {:4} {} -> {}", "OP_JUMP_IF_GIVEN", slot, offset, offset + 4 + jump);
+    offset + 4
+}
+
+// OP_JUMP_TABLE min len, then len + 1 backward 16-bit offsets: the default target first,
+// then one per value from min to min + len - 1. They are measured from the end of the table.
+fn jump_table_instruction(chunk: &Chunk, offset: usize) -> usize {
+    let code = chunk.code();
+    let min = chunk.constants().get(code[offset + 1] as usize).and_then(|value| value.as_int()).unwrap_or_default();
+    let len = code[offset + 2] as usize;
+    let end = offset + 3 + 2 * (len + 1);
+    println!("{:<16} {:4} min {} len {}", "OP_JUMP_TABLE", offset, min, len);
+    for entry in 0..=len {
+        let at = offset + 3 + 2 * entry;
+        let jump = u16::from_be_bytes([code[at], code[at + 1]]) as usize;
+        let label = if entry == 0 { "default".to_string() } else { format!("{}", min + entry as i64 - 1) };
+        println!("{:>22} {:>8} -> {}", "|", label, end - jump);
+    }
+    end
+}
+
 fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
     let constant_index = chunk.code()[offset + 1] as usize;
     print!("{:<16} {:4} '", name, constant_index);
@@ -75,4 +178,84 @@ fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
     
     // println!("'");
     offset + 2 // Because CONSTANT has a size of 2 bytes.
+}
+
+fn invoke_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let constant_index = chunk.code()[offset + 1] as usize;
+    let arg_count = chunk.code()[offset + 2];
+    print!("{:<16} ({} args) {:4} '", name, arg_count, constant_index);
+
+    if let Some(value) = chunk.constants().get(constant_index) {
+        print_value(value);
+    } else {
+        print!("INVALID_CONSTANT");
+    }
+    offset + 3
+}
+
+// The names of the named arguments follow the counts:
+/*
+    0012    | OP_CALL_NAMED    (3 args) b: c:
+*/
+fn call_named_instruction(chunk: &Chunk, offset: usize) -> usize {
+    let code = chunk.code();
+    let arg_count = code[offset + 1];
+    let named_count = code[offset + 2] as usize;
+    print!("{:<16} ({} args)", "OP_CALL_NAMED", arg_count);
+    for i in 0..named_count {
+        match chunk.constants().get(code[offset + 3 + i] as usize) {
+            Some(name) => print!(" {}:", name),
+            None => print!(" INVALID_CONSTANT"),
+        }
+    }
+    println!();
+    offset + 3 + named_count
+}
+
+// Which argument each parameter gets, `-` for one the call left out:
+/*
+    0012    | OP_SHUFFLE       (2 args) 1 - 0
+*/
+fn shuffle_instruction(chunk: &Chunk, offset: usize) -> usize {
+    let code = chunk.code();
+    let arg_count = code[offset + 1];
+    let parameter_count = code[offset + 2] as usize;
+    print!("{:<16} ({} args)", "OP_SHUFFLE", arg_count);
+    for &argument in &code[offset + 3..offset + 3 + parameter_count] {
+        if argument == NOT_GIVEN {
+            print!(" -");
+        } else {
+            print!(" {}", argument);
+        }
+    }
+    println!();
+    offset + 3 + parameter_count
+}
+
+fn closure_instruction(chunk: &Chunk, offset: usize) -> usize {
+    let mut offset = constant_instruction("OP_CLOSURE", chunk, offset);
+
+    // OP_CLOSURE is followed by a pair of bytes for every captured variable:
+    // is_local (1 = local of the enclosing function, 0 = one of its upvalues) and the index.
+    /*
+        0004    | OP_CLOSURE          1 '<fn counter>
+        0006    |                     local 1
+        0008    |                   upvalue 0
+    */
+    let upvalue_count = match chunk.constants().get(chunk.code()[offset - 1] as usize) {
+        Some(Value::Obj(object)) => object.as_function().map_or(0, |function| function.upvalue_count),
+        _ => 0,
+    };
+    for _ in 0..upvalue_count {
+        let is_local = chunk.code()[offset];
+        let index = chunk.code()[offset + 1];
+        println!(
+            "{:04}    |                     {} {}",
+            offset,
+            if is_local == 1 { "local" } else { "upvalue" },
+            index
+        );
+        offset += 2;
+    }
+    offset
 }
\ No newline at end of file
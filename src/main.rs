@@ -2,18 +2,19 @@ mod common;
 mod chunk;
 mod debug;
 mod value;
+mod number;
+mod bigint;
+#[allow(dead_code)] // Hand-built chunks, handy when debugging the VM without the compiler
 mod test_helpers;
 mod opcode;
-// mod memory;
+mod memory;
+mod object;
+mod table;
 mod vm;
+mod native;
 mod compiler;
 mod scanner;
 
-use chunk::{Chunk};
-use debug::disassemble_chunk;
-use crate::opcode::OpCode;
-use test_helpers::{create_simple_chunk, verify_chunk_structure};
-use test_helpers::{create_arithmetic_chunk, create_complex_arithmetic_chunk, create_complex_arithmetic_divide_zero_chunk};
 use vm::{VM, InterpretResult};
 
 use std::env;
@@ -26,12 +27,29 @@ fn main() {
     let args: Vec<String> = env::args().collect();
     
     let mut vm = VM::new();
+
+    // LOX_PATH lists extra directories to look for imported modules in, like PATH does for programs.
+    if let Some(search_paths) = env::var_os("LOX_PATH") {
+        for path in env::split_paths(&search_paths) {
+            vm.add_search_path(path);
+        }
+    }
+
+    // Flags for debugging the garbage collector, everything else is the script path.
+    let mut paths = Vec::new();
+    for arg in &args[1..] {
+        match arg.as_str() {
+            "--stress-gc" => vm.set_stress_gc(true),
+            "--log-gc" => vm.set_log_gc(true),
+            _ => paths.push(arg),
+        }
+    }
     
-    match args.len() {
-        1 => repl(&mut vm),
-        2 => run_file(&mut vm, &args[1]),
+    match paths.len() {
+        0 => repl(&mut vm),
+        1 => run_file(&mut vm, paths[0]),
         _ => {
-            eprintln!("Usage: {} [path]", args[0]);
+            eprintln!("Usage: {} [--stress-gc] [--log-gc] [path]", args[0]);
             process::exit(64);
         }
     }
@@ -68,6 +86,7 @@ fn run_file(vm: &mut VM, path: &str) {
         }
     };
     
+    vm.set_script_path(path);
     let result = run_source(vm, source);
     
     match result {
@@ -78,18 +97,11 @@ fn run_file(vm: &mut VM, path: &str) {
 }
 
 fn run_source(vm: &mut VM, source: String) -> InterpretResult {
-    if let Some(chunk) = compiler::compile(source) {
-        // Optional: Add this debug line to see the generated bytecode
-        // debug::disassemble_chunk(&chunk, "compiled expression");
-        
-        match vm.interpret(chunk) {
-            Ok(result) => result,
-            Err(err) => {
-                eprintln!("Runtime error: {}", err);
-                InterpretResult::RuntimeError
-            }
+    match vm.interpret(source) {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("Runtime error: {}", err);
+            InterpretResult::RuntimeError
         }
-    } else {
-        InterpretResult::CompileError
     }
 }
\ No newline at end of file
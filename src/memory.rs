@@ -0,0 +1,306 @@
+use std::mem::size_of;
+
+use crate::common::Value;
+use crate::object::{hash_string, Iterable, Obj, ObjRef, ObjString, UpvalueState};
+use crate::table::Table;
+use crate::vm::CallFrame;
+
+// After a collection, the next one happens once the heap has grown to this many times its live size.
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+// The heap keeps track of every object we have ever allocated,
+// so that we can free all of them when the VM goes away.
+// It also implements a tracing mark-and-sweep garbage collector:
+/*
+    1. mark:  the VM marks its roots (stack, globals, call frames, ...) gray
+    2. trace: pop a gray object, mark everything it references, until no gray object is left
+    3. sweep: every object that is still unmarked is unreachable, free it
+*/
+pub struct Heap {
+    objects: Vec<ObjRef>,
+    strings: Table, // Every string is interned here, so equal strings share one object
+    gray_stack: Vec<ObjRef>, // Marked objects whose references are not traced yet
+    bytes_allocated: usize,
+    next_gc: usize, // Collect once bytes_allocated goes above this
+    pub stress_gc: bool, // Collect before every allocation, to shake out rooting bugs
+    pub log_gc: bool, // Print statistics about every collection
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            strings: Table::new(),
+            gray_stack: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            stress_gc: false,
+            log_gc: false,
+        }
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress_gc || self.bytes_allocated > self.next_gc
+    }
+
+    // Allocating never collects by itself: only the VM knows the roots,
+    // so it checks should_collect() before calling this.
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = object_size(&obj);
+        self.bytes_allocated += size;
+        let reference = ObjRef::new(obj, size);
+        self.objects.push(reference);
+        reference
+    }
+
+    // Lists, maps, instances and the like keep growing after alloc() counted them. Whoever changes one
+    // calls this, so a script filling one big list still gets closer to the next collection.
+    pub fn remeasure(&mut self, object: ObjRef) {
+        let size = object_size(&object);
+        self.bytes_allocated = self.bytes_allocated - object.size() + size;
+        object.set_size(size);
+    }
+
+    pub fn alloc_string(&mut self, chars: String) -> ObjRef {
+        let hash = hash_string(&chars);
+        if let Some(interned) = self.strings.find_string(&chars, hash) {
+            return interned;
+        }
+
+        let string = self.alloc(Obj::String(ObjString { chars, hash }));
+        // We only care about the keys, the table is used like a hash set.
+        self.strings.set(Value::Obj(string), Value::Nil);
+        string
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(object) = value {
+            self.mark_object(object);
+        }
+    }
+
+    pub fn mark_object(&mut self, object: ObjRef) {
+        if object.is_marked() {
+            // Already marked, also stops us from looping forever on cycles.
+            return;
+        }
+        object.set_marked(true);
+        self.gray_stack.push(object);
+    }
+
+    pub fn mark_table(&mut self, table: &Table) {
+        for (key, value) in table.iter() {
+            self.mark_value(key);
+            self.mark_value(value);
+        }
+    }
+
+    // Called by the VM once all roots are marked.
+    pub fn collect_garbage(&mut self) {
+        let before = self.bytes_allocated;
+        if self.log_gc {
+            eprintln!("-- gc begin");
+        }
+
+        self.trace_references();
+        // The string table does not keep strings alive, it only remembers the ones that are alive.
+        self.strings.remove_unmarked();
+        let freed = self.sweep();
+
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_INITIAL_THRESHOLD);
+
+        if self.log_gc {
+            eprintln!("-- gc end");
+            eprintln!(
+                "   collected {} bytes ({} objects) (from {} to {}) next at {}",
+                before - self.bytes_allocated,
+                freed,
+                before,
+                self.bytes_allocated,
+                self.next_gc
+            );
+        }
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(object) = self.gray_stack.pop() {
+            self.blacken_object(object);
+        }
+    }
+
+    // Mark everything this object refers to.
+    fn blacken_object(&mut self, object: ObjRef) {
+        match &*object {
+            Obj::String(_) => {}
+            Obj::Function(function) => {
+                if let Some(name) = function.name {
+                    self.mark_object(name);
+                }
+                self.mark_object(function.module);
+                for &parameter in &function.parameters {
+                    self.mark_object(parameter);
+                }
+                for &constant in function.chunk.constants().values() {
+                    self.mark_value(constant);
+                }
+            }
+            Obj::Closure(closure) => {
+                self.mark_object(closure.function);
+                for &upvalue in &closure.upvalues {
+                    self.mark_object(upvalue);
+                }
+            }
+            Obj::Upvalue(upvalue) => {
+                // An open upvalue points into the stack, which is marked as a root anyway.
+                match upvalue.state.get() {
+                    UpvalueState::Open(_) => {}
+                    UpvalueState::Suspended(generator, _) => self.mark_object(generator),
+                    UpvalueState::Closed(value) => self.mark_value(value),
+                }
+            }
+            Obj::Class(class) => {
+                self.mark_object(class.name);
+                self.mark_table(&class.methods.borrow());
+            }
+            Obj::Instance(instance) => {
+                self.mark_object(instance.class);
+                self.mark_table(&instance.fields.borrow());
+            }
+            Obj::BoundMethod(bound) => {
+                self.mark_value(bound.receiver);
+                self.mark_object(bound.method);
+            }
+            Obj::Native(native) => self.mark_object(native.name),
+            Obj::List(list) => {
+                for &item in list.items.borrow().iter() {
+                    self.mark_value(item);
+                }
+            }
+            Obj::Map(map) => self.mark_table(&map.entries.borrow()),
+            Obj::BigInt(_) => {}
+            Obj::Fiber(fiber) => {
+                for &value in fiber.stack.borrow().iter() {
+                    self.mark_value(value);
+                }
+                for frame in fiber.frames.borrow().iter() {
+                    self.mark_object(frame.closure);
+                    if let Some(generator) = frame.generator {
+                        self.mark_object(generator);
+                    }
+                }
+                for &upvalue in fiber.upvalues.borrow().iter() {
+                    self.mark_object(upvalue);
+                }
+            }
+            Obj::Channel(channel) => {
+                for &value in channel.buffer.borrow().iter() {
+                    self.mark_value(value);
+                }
+                for &fiber in channel.waiting.borrow().iter() {
+                    self.mark_object(fiber);
+                }
+            }
+            Obj::Generator(generator) => {
+                self.mark_object(generator.closure);
+                for &value in generator.stack.borrow().iter() {
+                    self.mark_value(value);
+                }
+                for &upvalue in generator.upvalues.borrow().iter() {
+                    self.mark_object(upvalue);
+                }
+            }
+            Obj::Iterator(iterator) => match iterator.iterable {
+                Iterable::List(object) | Iterable::String(object) | Iterable::MapKeys(object) => {
+                    self.mark_object(object)
+                }
+                Iterable::Range { .. } => {}
+            },
+            Obj::Module(module) => {
+                self.mark_object(module.name);
+                self.mark_table(&module.globals.borrow());
+                self.mark_table(&module.exports.borrow());
+                self.mark_table(&module.const_globals.borrow());
+            }
+        }
+    }
+
+    fn sweep(&mut self) -> usize {
+        let mut freed = 0;
+        let mut bytes_freed = 0;
+        self.objects.retain(|&object| {
+            if object.is_marked() {
+                // Unmark for the next collection.
+                object.set_marked(false);
+                true
+            } else {
+                // Take back exactly what alloc() and remeasure() counted, measuring the object again now
+                // could give a different answer and slowly drive bytes_allocated away from the truth.
+                bytes_freed += object.size();
+                freed += 1;
+                // Nothing can reach this object anymore, so nobody holds a handle to it.
+                unsafe { object.free() };
+                false
+            }
+        });
+        self.bytes_allocated -= bytes_freed;
+        freed
+    }
+}
+
+// A rough estimate of how much memory an object owns right now, used to decide when to collect.
+fn object_size(obj: &Obj) -> usize {
+    size_of::<Obj>()
+        + match obj {
+            Obj::String(string) => string.chars.capacity(),
+            Obj::Function(function) => {
+                function.chunk.count() * (size_of::<u8>() + size_of::<usize>())
+                    + function.chunk.constants().count() * size_of::<Value>()
+                    + function.parameters.capacity() * size_of::<ObjRef>()
+            }
+            Obj::Closure(closure) => closure.upvalues.capacity() * size_of::<ObjRef>(),
+            Obj::Upvalue(_) => 0,
+            Obj::Class(class) => class.methods.borrow().capacity() * size_of::<(Value, Value)>(),
+            Obj::Instance(instance) => instance.fields.borrow().capacity() * size_of::<(Value, Value)>(),
+            Obj::BoundMethod(_) => 0,
+            Obj::Native(_) => 0,
+            Obj::List(list) => list.items.borrow().capacity() * size_of::<Value>(),
+            Obj::Map(map) => map.entries.borrow().capacity() * size_of::<(Value, Value)>(),
+            Obj::Iterator(_) => 0,
+            Obj::Fiber(fiber) => {
+                fiber.stack.borrow().capacity() * size_of::<Value>()
+                    + fiber.frames.borrow().capacity() * size_of::<CallFrame>()
+                    + fiber.upvalues.borrow().capacity() * size_of::<ObjRef>()
+            }
+            Obj::Channel(channel) => {
+                channel.buffer.borrow().capacity() * size_of::<Value>()
+                    + channel.waiting.borrow().capacity() * size_of::<ObjRef>()
+            }
+            Obj::Generator(generator) => {
+                generator.stack.borrow().capacity() * size_of::<Value>()
+                    + generator.upvalues.borrow().capacity() * size_of::<ObjRef>()
+            }
+            Obj::BigInt(big) => big.value.limb_count() * size_of::<u32>(),
+            Obj::Module(module) => {
+                (module.globals.borrow().capacity()
+                    + module.exports.borrow().capacity()
+                    + module.const_globals.borrow().capacity())
+                    * size_of::<(Value, Value)>()
+            }
+        }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for object in self.objects.drain(..) {
+            // Nothing can outlive the heap, so it is safe to free everything here.
+            unsafe { object.free() };
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+
+use crate::bigint::BigInt;
+use crate::common::Value;
+use crate::object::{Iterable, Obj, ObjChannel, ObjIterator, ObjList, ObjMap};
+use crate::vm::VM;
+
+// The functions every script can use without defining them.
+pub fn define_natives(vm: &mut VM) {
+    vm.define_native("clock", 0, clock);
+    vm.define_native("type", 1, type_);
+    vm.define_native("str", 1, str);
+    vm.define_native("num", 1, num);
+    vm.define_native("len", 1, len);
+    vm.define_native("push", 2, push);
+    vm.define_native("pop", 1, pop);
+    vm.define_native("has", 2, has);
+    vm.define_native("remove", 2, remove);
+    vm.define_native("keys", 1, keys);
+    vm.define_native("range", 2, range);
+    vm.define_native("spawn", 1, spawn);
+    vm.define_native("pause", 0, pause);
+    vm.define_native("channel", 1, channel);
+}
+
+// Seconds since the Unix epoch, scripts subtract two calls to time themselves.
+fn clock(_vm: &mut VM, _args: &[Value]) -> Result<Value> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    Ok(Value::Number(now.as_secs_f64()))
+}
+
+// type(1) == "int", type("a") == "string", type(nil) == "nil", ...
+fn type_(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let name = vm.alloc_string(args[0].type_name().to_string());
+    Ok(Value::Obj(name))
+}
+
+// Any value as the string `print` would show.
+fn str(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    if args[0].is_string() {
+        return Ok(args[0]);
+    }
+    let string = vm.alloc_string(args[0].to_string());
+    Ok(Value::Obj(string))
+}
+
+// num("3.5") == 3.5 and num("3") == 3, numbers are returned unchanged.
+fn num(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    match args[0] {
+        Value::Int(_) | Value::Number(_) => Ok(args[0]),
+        value => {
+            let Some(string) = value.as_string() else {
+                bail!("Can't convert a {} to a number.", value.type_name());
+            };
+            let string = string.trim();
+            // Same rule as literals: no '.' means an int.
+            if let Ok(n) = string.parse::<i64>() {
+                return Ok(Value::Int(n));
+            }
+            let (negative, digits) = match string.strip_prefix('-') {
+                Some(digits) => (true, digits),
+                None => (false, string),
+            };
+            if let Some(big) = BigInt::parse(digits) {
+                return Ok(vm.big_int(if negative { big.neg() } else { big }));
+            }
+            match string.parse::<f64>() {
+                Ok(n) => Ok(Value::Number(n)),
+                Err(_) => bail!("Can't convert '{}' to a number.", value),
+            }
+        }
+    }
+}
+
+// Number of items in a list, entries in a map, or characters in a string.
+fn len(_vm: &mut VM, args: &[Value]) -> Result<Value> {
+    if let Some(string) = args[0].as_string() {
+        return Ok(Value::Int(string.chars().count() as i64));
+    }
+    if let Ok(map) = expect_map(&args[0]) {
+        return Ok(Value::Int(map.entries.borrow().len() as i64));
+    }
+    let list = expect_list(&args[0])?;
+    let len = list.items.borrow().len();
+    Ok(Value::Int(len as i64))
+}
+
+// Append an item to the end of a list.
+fn push(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    expect_list(&args[0])?.items.borrow_mut().push(args[1]);
+    if let Value::Obj(list) = args[0] {
+        vm.remeasure(list);
+    }
+    Ok(Value::Nil)
+}
+
+// Remove the last item of a list and return it.
+fn pop(_vm: &mut VM, args: &[Value]) -> Result<Value> {
+    match expect_list(&args[0])?.items.borrow_mut().pop() {
+        Some(item) => Ok(item),
+        None => bail!("Can't pop from an empty list."),
+    }
+}
+
+fn expect_list(value: &Value) -> Result<&ObjList> {
+    match value {
+        Value::Obj(object) if object.as_list().is_some() => Ok(object.as_list().expect("Checked above")),
+        _ => bail!("Expected a list but got a {}.", value.type_name()),
+    }
+}
+
+// Whether a map has an entry for the key.
+fn has(_vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let key = expect_key(args[1])?;
+    let found = expect_map(&args[0])?.entries.borrow().get(key).is_some();
+    Ok(Value::Bool(found))
+}
+
+// Remove an entry from a map, returns whether there was one.
+fn remove(_vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let key = expect_key(args[1])?;
+    let removed = expect_map(&args[0])?.entries.borrow_mut().delete(key);
+    Ok(Value::Bool(removed))
+}
+
+// A new list with every key of a map, in no particular order.
+fn keys(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let keys = expect_map(&args[0])?.entries.borrow().iter().map(|(key, _)| key).collect();
+    // The keys are still reachable from the map (an argument) while the list is allocated.
+    let list = vm.alloc(Obj::List(ObjList::new(keys)));
+    Ok(Value::Obj(list))
+}
+
+// range(0, 3) iterates over 0, 1 and 2 without building a list.
+fn range(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let (Value::Int(start), Value::Int(end)) = (args[0], args[1]) else {
+        bail!("Expected two integers but got a {} and a {}.", args[0].type_name(), args[1].type_name());
+    };
+    let iterator = ObjIterator { iterable: Iterable::Range { end }, position: Cell::new(start) };
+    Ok(Value::Obj(vm.alloc(Obj::Iterator(iterator))))
+}
+
+// Start a fiber running the function, see VM::spawn.
+fn spawn(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    vm.spawn(args[0])
+}
+
+// Let the other fibers run, this one continues once they all had their turn.
+fn pause(vm: &mut VM, _args: &[Value]) -> Result<Value> {
+    vm.request_pause();
+    Ok(Value::Nil)
+}
+
+// channel(2) holds up to 2 values that were sent but not received yet.
+fn channel(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let capacity = match args[0] {
+        Value::Int(capacity) if capacity >= 1 => capacity as usize,
+        Value::Int(_) => bail!("A channel must hold at least one value."),
+        value => bail!("Expected an integer capacity but got a {}.", value.type_name()),
+    };
+    let channel = ObjChannel {
+        buffer: RefCell::new(VecDeque::new()),
+        capacity,
+        closed: Cell::new(false),
+        waiting: RefCell::new(Vec::new()),
+    };
+    Ok(Value::Obj(vm.alloc(Obj::Channel(channel))))
+}
+
+fn expect_map(value: &Value) -> Result<&ObjMap> {
+    match value {
+        Value::Obj(object) if object.as_map().is_some() => Ok(object.as_map().expect("Checked above")),
+        _ => bail!("Expected a map but got a {}.", value.type_name()),
+    }
+}
+
+fn expect_key(key: Value) -> Result<Value> {
+    if !key.is_hashable() {
+        bail!("A {} can't be used as a map key.", key.type_name());
+    }
+    Ok(key)
+}
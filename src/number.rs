@@ -0,0 +1,191 @@
+use std::cmp::Ordering;
+
+use crate::bigint::BigInt;
+use crate::common::Value;
+
+// The two operands of an arithmetic operator, after the promotion rules:
+/*
+    int    op int    -> int, or a bignum when the result does not fit in 64 bits
+    bignum op int    -> bignum (the int is promoted)
+    bignum op bignum -> bignum
+    any    op float  -> float
+*/
+// Results that fit in an int again are turned back into ints by the VM, see VM::big_int.
+pub enum Operands {
+    Ints(i64, i64),
+    Bigs(BigInt, BigInt),
+    Floats(f64, f64),
+}
+
+// The result of an operator, before it becomes a Value:
+// only the VM can allocate the object a bignum lives in.
+pub enum Computed {
+    Value(Value),
+    Big(BigInt),
+}
+
+impl From<Value> for Computed {
+    fn from(value: Value) -> Self {
+        Computed::Value(value)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Arithmetic {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+// None when one of the values is not a number at all.
+pub fn promote(a: Value, b: Value) -> Option<Operands> {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => Some(Operands::Ints(a, b)),
+        (Value::Number(a), b) => Some(Operands::Floats(a, b.as_number()?)),
+        (a, Value::Number(b)) => Some(Operands::Floats(a.as_number()?, b)),
+        (a, b) => Some(Operands::Bigs(to_big(a)?, to_big(b)?)),
+    }
+}
+
+// Ints and bignums as a bignum.
+fn to_big(value: Value) -> Option<BigInt> {
+    match value {
+        Value::Int(n) => Some(BigInt::from_i64(n)),
+        Value::Obj(object) => object.as_bigint().map(|big| big.value.clone()),
+        _ => None,
+    }
+}
+
+// + - * / % on any two numbers.
+pub fn arithmetic(op: Arithmetic, a: Value, b: Value) -> Result<Computed, String> {
+    let Some(operands) = promote(a, b) else {
+        return Err(match op {
+            Arithmetic::Add => "Operands must be two numbers or two strings.",
+            _ => "Operands must be numbers.",
+        }
+        .to_string());
+    };
+    match operands {
+        Operands::Ints(a, b) => {
+            let result = match op {
+                Arithmetic::Add => a.checked_add(b),
+                Arithmetic::Subtract => a.checked_sub(b),
+                Arithmetic::Multiply => a.checked_mul(b),
+                // Dividing two ints truncates towards zero: 7 / 2 == 3, 7 / 2.0 == 3.5.
+                Arithmetic::Divide if b == 0 => return Err("Division by zero".to_string()),
+                Arithmetic::Divide => a.checked_div(b),
+                // The result has the sign of the dividend, like in C: -7 % 3 == -1.
+                Arithmetic::Modulo if b == 0 => return Err("Division by zero".to_string()),
+                Arithmetic::Modulo => a.checked_rem(b),
+            };
+            match result {
+                Some(n) => Ok(Value::Int(n).into()),
+                // It overflowed, so do it again without a size limit.
+                None => big_arithmetic(op, BigInt::from_i64(a), BigInt::from_i64(b)),
+            }
+        }
+        Operands::Bigs(a, b) => big_arithmetic(op, a, b),
+        Operands::Floats(a, b) => match op {
+            Arithmetic::Add => Ok(Value::Number(a + b).into()),
+            Arithmetic::Subtract => Ok(Value::Number(a - b).into()),
+            Arithmetic::Multiply => Ok(Value::Number(a * b).into()),
+            Arithmetic::Divide | Arithmetic::Modulo if b == 0.0 => Err("Division by zero".to_string()),
+            Arithmetic::Divide => Ok(Value::Number(a / b).into()),
+            Arithmetic::Modulo => Ok(Value::Number(a % b).into()),
+        },
+    }
+}
+
+fn big_arithmetic(op: Arithmetic, a: BigInt, b: BigInt) -> Result<Computed, String> {
+    let result = match op {
+        Arithmetic::Add => a.add(&b),
+        Arithmetic::Subtract => a.sub(&b),
+        Arithmetic::Multiply => a.mul(&b),
+        Arithmetic::Divide => a.div_rem(&b).ok_or("Division by zero")?.0,
+        Arithmetic::Modulo => a.div_rem(&b).ok_or("Division by zero")?.1,
+    };
+    Ok(Computed::Big(result))
+}
+
+pub fn negate(value: Value) -> Result<Computed, String> {
+    match value {
+        // -i64::MIN does not fit in an i64.
+        Value::Int(n) => match n.checked_neg() {
+            Some(n) => Ok(Value::Int(n).into()),
+            None => Ok(Computed::Big(BigInt::from_i64(n).neg())),
+        },
+        Value::Number(n) => Ok(Value::Number(-n).into()),
+        _ => match to_big(value) {
+            Some(big) => Ok(Computed::Big(big.neg())),
+            None => Err("Operand must be a number.".to_string()),
+        },
+    }
+}
+
+// None when the numbers can't be ordered, which only happens with NaN.
+pub fn compare(a: Value, b: Value) -> Result<Option<Ordering>, String> {
+    match promote(a, b) {
+        Some(Operands::Ints(a, b)) => Ok(Some(a.cmp(&b))),
+        Some(Operands::Bigs(a, b)) => Ok(Some(a.cmp(&b))),
+        Some(Operands::Floats(a, b)) => Ok(a.partial_cmp(&b)),
+        None => Err("Operands must be numbers.".to_string()),
+    }
+}
+
+// Numbers are equal when their values are, whatever their types: 1 == 1.0.
+pub fn equal(a: Value, b: Value) -> bool {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => a == b,
+        (Value::Number(a), Value::Number(b)) => a == b,
+        // Comparing as floats would round big ints, so compare exactly.
+        (Value::Number(n), other) | (other, Value::Number(n)) => {
+            BigInt::from_f64(n).is_some_and(|n| Some(n) == to_big(other))
+        }
+        _ => match (to_big(a), to_big(b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        },
+    }
+}
+
+// The float with the same value as an int, if there is one: 3.0 -> 3, 3.5 -> None.
+pub fn float_to_int(n: f64) -> Option<i64> {
+    // i64::MAX as f64 rounds up to 2^63, which is already out of range, hence the strict <.
+    if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+        Some(n as i64)
+    } else {
+        None
+    }
+}
+
+// Operators that only make sense on integers: & | ^ << >>
+pub fn bitwise(a: Value, b: Value, op: fn(i64, i64) -> Result<i64, String>) -> Result<Computed, String> {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => op(a, b).map(|n| Value::Int(n).into()),
+        _ => Err("Operands must be integers.".to_string()),
+    }
+}
+
+// The shift amount must fit in the 64 bits, `>>` keeps the sign like Rust and C do.
+pub fn shift_amount(amount: i64) -> Result<u32, String> {
+    if (0..64).contains(&amount) {
+        Ok(amount as u32)
+    } else {
+        Err("Shift amount must be between 0 and 63.".to_string())
+    }
+}
+
+// Unlike + - and *, `<<` does not turn into a bignum (the other bitwise operators only work on ints),
+// but it does not quietly drop the bits shifted out either: 1 << 63 is an error, not i64::MIN.
+pub fn shift_left(a: i64, amount: i64) -> Result<i64, String> {
+    let amount = shift_amount(amount)?;
+    let shifted = a << amount;
+    // Shifting back only gives `a` again when no bit (the sign included) was lost.
+    if shifted >> amount == a {
+        Ok(shifted)
+    } else {
+        Err("Left shift overflows 64 bits, multiply by a power of two for a bignum.".to_string())
+    }
+}
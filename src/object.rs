@@ -0,0 +1,562 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::ptr::NonNull;
+
+use crate::bigint::BigInt;
+use crate::chunk::Chunk;
+use crate::common::Value;
+use crate::table::Table;
+use crate::vm::{CallFrame, VM};
+
+// Everything that is too big (or too dynamic) to fit inside a `Value` lives on the heap
+// as an `Obj`. A `Value` only carries a small `ObjRef` handle pointing to it.
+pub enum Obj {
+    String(ObjString),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    Native(ObjNative),
+    List(ObjList),
+    Map(ObjMap),
+    Module(ObjModule),
+    BigInt(ObjBigInt),
+    Iterator(ObjIterator),
+    Generator(ObjGenerator),
+    Fiber(ObjFiber),
+    Channel(ObjChannel),
+}
+
+pub struct ObjString {
+    pub chars: String,
+    pub hash: u32, // Cached so that hash tables do not need to rehash the string every time
+}
+
+// FNV-1a, the same hash function clox uses.
+pub fn hash_string(chars: &str) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for byte in chars.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+// Every function, including the top-level script, is compiled into its own chunk.
+pub struct ObjFunction {
+    pub arity: usize, // Parameters before the rest parameter, the ones with a default value included
+    pub optional: usize, // How many of the last of those have a default value
+    pub has_rest: bool, // The last parameter is `...name`, it gets the extra arguments as a list
+    pub parameters: Vec<ObjRef>, // The parameter names, the rest parameter last, for named arguments and error messages
+    pub upvalue_count: usize, // How many variables from enclosing functions it captures
+    pub chunk: Chunk,
+    pub name: Option<ObjRef>, // None for the top-level script
+    pub module: ObjRef, // The module it was compiled in, its global variables are the module's
+    pub is_generator: bool, // Declared with `fun*`: calling it creates a generator instead of running it
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<ObjRef>, module: ObjRef) -> Self {
+        Self {
+            arity: 0,
+            optional: 0,
+            has_rest: false,
+            parameters: Vec::new(),
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name,
+            module,
+            is_generator: false,
+        }
+    }
+}
+
+impl fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "<fn {}>", *name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+// A function together with the variables it captured from the functions around it.
+// The compiler only produces functions, closures are created at runtime by OP_CLOSURE.
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>, // One ObjUpvalue per captured variable
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum UpvalueState {
+    Open(usize), // The variable is still alive on the VM stack, at this index
+    Suspended(ObjRef, usize), // The variable is on the saved stack of a generator or fiber that is not running, at this index
+    Closed(Value), // The variable went out of scope, so the upvalue keeps the value itself
+}
+
+// A reference to a captured variable.
+/*
+    fun makeCounter() {
+        var count = 0;             <- while makeCounter runs, `count` is on the stack (Open)
+        fun counter() { count = count + 1; return count; }
+        return counter;            <- after it returns, `count` moves into the upvalue (Closed)
+    }
+*/
+pub struct ObjUpvalue {
+    pub state: Cell<UpvalueState>, // Cell, because closures sharing this upvalue all write through it
+}
+
+impl ObjUpvalue {
+    pub fn new(slot: usize) -> Self {
+        Self {
+            state: Cell::new(UpvalueState::Open(slot)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeneratorState {
+    Suspended, // Created, or stopped at a yield
+    Running, // Its frame is on the call stack
+    Done, // Returned or threw, next() gives nil from now on
+}
+
+// The paused call of a `fun*` function. Calling the function only creates this;
+// every next() moves the saved stack segment back onto the VM stack and runs until the next yield:
+/*
+    fun* count() { var i = 0; while (true) { yield i; i = i + 1; } }
+    var g = count();     stack: [g]                  saved: [count] ip: 0
+    g.next();            stack: [count][i]           running, yields 0
+                         stack: [0]                  saved: [count][i] ip: after the yield
+*/
+pub struct ObjGenerator {
+    pub closure: ObjRef,
+    pub ip: Cell<usize>,
+    pub stack: RefCell<Vec<Value>>, // Slot 0 (the closure) and the locals, only while suspended
+    pub upvalues: RefCell<Vec<ObjRef>>, // Upvalues pointing into `stack`, in slot order
+    pub state: Cell<GeneratorState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FiberState {
+    Ready, // Waiting in the scheduler's queue for its turn
+    Running,
+    Blocked, // Waiting on a channel, only the channel can make it ready again
+    Done,
+}
+
+// A separate line of execution with its own stack and call frames. Only one fiber runs at a time:
+// the running one keeps its state in the VM itself, the others keep it here until the scheduler switches to them.
+pub struct ObjFiber {
+    pub stack: RefCell<Vec<Value>>,
+    pub frames: RefCell<Vec<CallFrame>>,
+    pub upvalues: RefCell<Vec<ObjRef>>, // Its open upvalues, Suspended while it is not running
+    pub state: Cell<FiberState>,
+}
+
+impl ObjFiber {
+    pub fn new(stack: Vec<Value>, frames: Vec<CallFrame>, state: FiberState) -> Self {
+        Self {
+            stack: RefCell::new(stack),
+            frames: RefCell::new(frames),
+            upvalues: RefCell::new(Vec::new()),
+            state: Cell::new(state),
+        }
+    }
+}
+
+// A queue of values fibers use to talk to each other. Sending to a full channel or receiving
+// from an empty one blocks the fiber until another fiber makes room or sends something.
+pub struct ObjChannel {
+    pub buffer: RefCell<VecDeque<Value>>,
+    pub capacity: usize,
+    pub closed: Cell<bool>, // No more sends, receive() gives nil once the buffer is empty
+    pub waiting: RefCell<Vec<ObjRef>>, // Fibers blocked on this channel, in the order they blocked
+}
+
+pub struct ObjClass {
+    pub name: ObjRef,
+    pub methods: RefCell<Table>, // Filled in by OP_METHOD after the class is created
+}
+
+impl ObjClass {
+    pub fn new(name: ObjRef) -> Self {
+        Self {
+            name,
+            methods: RefCell::new(Table::new()),
+        }
+    }
+}
+
+pub struct ObjInstance {
+    pub class: ObjRef,
+    pub fields: RefCell<Table>, // Fields can be added to an instance at any time
+}
+
+impl ObjInstance {
+    pub fn new(class: ObjRef) -> Self {
+        Self {
+            class,
+            fields: RefCell::new(Table::new()),
+        }
+    }
+}
+
+// A method that remembers which instance it was accessed from:
+/*
+    var m = instance.method;  <- m is a bound method
+    m();                      <- `this` inside the method is still `instance`
+*/
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef, // Always a closure
+}
+
+// A function implemented in Rust. It gets a copy of its arguments and can use the VM to allocate,
+// an Err is reported to the script as a runtime error.
+pub type NativeFn = fn(&mut VM, &[Value]) -> anyhow::Result<Value>;
+
+pub struct ObjNative {
+    pub name: ObjRef,
+    pub arity: usize, // Checked by the VM before the function is called
+    pub function: NativeFn,
+}
+
+pub struct ObjList {
+    pub items: RefCell<Vec<Value>>, // Lists are mutable, `push` and `list[i] = v` change them in place
+}
+
+impl ObjList {
+    pub fn new(items: Vec<Value>) -> Self {
+        Self {
+            items: RefCell::new(items),
+        }
+    }
+}
+
+// Keys are checked with Value::is_hashable before they go into the table.
+pub struct ObjMap {
+    pub entries: RefCell<Table>,
+}
+
+impl ObjMap {
+    pub fn new() -> Self {
+        Self {
+            entries: RefCell::new(Table::new()),
+        }
+    }
+}
+
+// One source file. Every module has its own global variables,
+// other modules only see the ones it declares with `export`.
+pub struct ObjModule {
+    pub name: ObjRef, // The canonical path of the file, "<script>" for code typed into the REPL
+    pub directory: PathBuf, // Imports inside this module are resolved relative to it
+    pub globals: RefCell<Table>,
+    pub exports: RefCell<Table>, // Exported names, used as a set
+    pub const_globals: RefCell<Table>, // Names of the globals declared with `const`, used as a set
+    pub is_loaded: Cell<bool>, // False while its top-level code runs, importing it then is a cycle
+}
+
+impl ObjModule {
+    pub fn new(name: ObjRef, directory: PathBuf) -> Self {
+        Self {
+            name,
+            directory,
+            globals: RefCell::new(Table::new()),
+            exports: RefCell::new(Table::new()),
+            const_globals: RefCell::new(Table::new()),
+            is_loaded: Cell::new(false),
+        }
+    }
+}
+
+// What a built-in iterator walks over. User objects bring their own iterator() and next() methods instead.
+pub enum Iterable {
+    List(ObjRef),
+    String(ObjRef),
+    MapKeys(ObjRef),
+    Range { end: i64 }, // Counts up from the position to end, which is excluded
+}
+
+// Created by OP_GET_ITER for `for (x in ...)` loops, and by the range() native.
+// What the position means depends on the iterable:
+/*
+    List     index of the next item
+    String   byte offset of the next character
+    MapKeys  slot of the map's table to look at next (see Table::next_entry)
+    Range    the next number
+*/
+pub struct ObjIterator {
+    pub iterable: Iterable,
+    pub position: Cell<i64>,
+}
+
+// An int too big for 64 bits. The VM only creates one when the value does not fit in an i64,
+// so a bignum is never equal to a Value::Int.
+pub struct ObjBigInt {
+    pub value: BigInt,
+}
+
+impl Obj {
+    pub fn as_string(&self) -> Option<&ObjString> {
+        match self {
+            Obj::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_function(&self) -> Option<&ObjFunction> {
+        match self {
+            Obj::Function(function) => Some(function),
+            _ => None,
+        }
+    }
+
+    pub fn as_closure(&self) -> Option<&ObjClosure> {
+        match self {
+            Obj::Closure(closure) => Some(closure),
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue(&self) -> Option<&ObjUpvalue> {
+        match self {
+            Obj::Upvalue(upvalue) => Some(upvalue),
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self) -> Option<&ObjClass> {
+        match self {
+            Obj::Class(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self) -> Option<&ObjInstance> {
+        match self {
+            Obj::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&ObjList> {
+        match self {
+            Obj::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&ObjMap> {
+        match self {
+            Obj::Map(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    pub fn as_bigint(&self) -> Option<&ObjBigInt> {
+        match self {
+            Obj::BigInt(big) => Some(big),
+            _ => None,
+        }
+    }
+
+    pub fn as_generator(&self) -> Option<&ObjGenerator> {
+        match self {
+            Obj::Generator(generator) => Some(generator),
+            _ => None,
+        }
+    }
+
+    pub fn as_fiber(&self) -> Option<&ObjFiber> {
+        match self {
+            Obj::Fiber(fiber) => Some(fiber),
+            _ => None,
+        }
+    }
+
+    pub fn as_channel(&self) -> Option<&ObjChannel> {
+        match self {
+            Obj::Channel(channel) => Some(channel),
+            _ => None,
+        }
+    }
+
+    // Where a Suspended upvalue's variable is.
+    pub fn saved_stack(&self) -> &RefCell<Vec<Value>> {
+        match self {
+            Obj::Generator(generator) => &generator.stack,
+            Obj::Fiber(fiber) => &fiber.stack,
+            _ => unreachable!("Only generators and fibers suspend"),
+        }
+    }
+
+    pub fn as_iterator(&self) -> Option<&ObjIterator> {
+        match self {
+            Obj::Iterator(iterator) => Some(iterator),
+            _ => None,
+        }
+    }
+
+    pub fn as_module(&self) -> Option<&ObjModule> {
+        match self {
+            Obj::Module(module) => Some(module),
+            _ => None,
+        }
+    }
+}
+
+thread_local! {
+    // The objects we are in the middle of printing, innermost last.
+    static PRINTING: RefCell<Vec<*const Obj>> = const { RefCell::new(Vec::new()) };
+}
+
+// A list or map can contain itself (`push(a, a)`), and printing it naively would recurse forever.
+// So while we print the items of an object we remember it, and when it comes up again
+// inside itself we print `cycle` instead of going around once more.
+fn print_once(
+    obj: &Obj,
+    f: &mut fmt::Formatter<'_>,
+    cycle: &str,
+    print: impl FnOnce(&mut fmt::Formatter<'_>) -> fmt::Result,
+) -> fmt::Result {
+    let pointer = obj as *const Obj;
+    if PRINTING.with_borrow(|printing| printing.contains(&pointer)) {
+        return write!(f, "{}", cycle);
+    }
+
+    PRINTING.with_borrow_mut(|printing| printing.push(pointer));
+    let result = print(f);
+    PRINTING.with_borrow_mut(|printing| printing.pop());
+    result
+}
+
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Obj::String(s) => write!(f, "{}", s.chars),
+            Obj::Function(function) => write!(f, "{}", function),
+            // A closure prints like the function it wraps, users never see the difference.
+            Obj::Closure(closure) => write!(f, "{}", *closure.function),
+            Obj::Upvalue(_) => write!(f, "upvalue"),
+            Obj::Class(class) => write!(f, "{}", *class.name),
+            Obj::Instance(instance) => {
+                let class = instance.class.as_class().expect("Instances always have a class");
+                write!(f, "{} instance", *class.name)
+            }
+            Obj::BoundMethod(bound) => write!(f, "{}", *bound.method),
+            Obj::Native(_) => write!(f, "<native fn>"),
+            Obj::BigInt(big) => write!(f, "{}", big.value),
+            Obj::Iterator(_) => write!(f, "<iterator>"),
+            Obj::Fiber(_) => write!(f, "<fiber>"),
+            Obj::Channel(_) => write!(f, "<channel>"),
+            Obj::Generator(generator) => {
+                let function = generator.closure.as_closure().expect("Generators run closures").function;
+                let name = function.as_function().expect("Closures always wrap functions").name;
+                write!(f, "<generator {}>", *name.expect("Only named functions can be generators"))
+            }
+            Obj::List(list) => print_once(self, f, "[...]", |f| {
+                write!(f, "[")?;
+                for (i, item) in list.items.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }),
+            Obj::Map(map) => print_once(self, f, "{...}", |f| {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.entries.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key, value)?;
+                }
+                write!(f, "}}")
+            }),
+            Obj::Module(module) => write!(f, "<module {}>", *module.name),
+        }
+    }
+}
+
+// Every object on the heap carries a mark bit for the garbage collector.
+struct HeapObject {
+    is_marked: Cell<bool>,
+    size: Cell<usize>, // What the heap counted for it so far, and takes back off when freeing it
+    obj: Obj,
+}
+
+// A handle to an object allocated by `memory::Heap`.
+// Copying the handle does NOT copy the object, it is just like copying a pointer in C.
+// The heap owns every object and is the only one allowed to free it.
+#[derive(Clone, Copy)]
+pub struct ObjRef {
+    ptr: NonNull<HeapObject>,
+}
+
+impl ObjRef {
+    pub(crate) fn new(obj: Obj, size: usize) -> Self {
+        let object = Box::new(HeapObject {
+            is_marked: Cell::new(false),
+            size: Cell::new(size),
+            obj,
+        });
+        Self {
+            ptr: NonNull::from(Box::leak(object)),
+        }
+    }
+
+    // Safety: the caller must guarantee that nobody will use this handle (or any copy of it) again.
+    pub(crate) unsafe fn free(self) {
+        drop(unsafe { Box::from_raw(self.ptr.as_ptr()) });
+    }
+
+    pub(crate) fn is_marked(&self) -> bool {
+        self.header().is_marked.get()
+    }
+
+    pub(crate) fn set_marked(&self, is_marked: bool) {
+        self.header().is_marked.set(is_marked);
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.header().size.get()
+    }
+
+    pub(crate) fn set_size(&self, size: usize) {
+        self.header().size.set(size);
+    }
+
+    fn header(&self) -> &HeapObject {
+        // The heap keeps the object alive for as long as a handle to it can be reached.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl Deref for ObjRef {
+    type Target = Obj;
+
+    fn deref(&self) -> &Obj {
+        &self.header().obj
+    }
+}
+
+// Two handles are equal only when they point to the very same object.
+impl PartialEq for ObjRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl fmt::Debug for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", **self)
+    }
+}
@@ -13,7 +13,58 @@ pub enum OpCode {
     Not = 10,           // Logical NOT (!)
     Equal = 11,         // ==
     Greater = 12,       // >
-    Less = 13,          // 
+    Less = 13,          // <
+    Print = 14,         // print statement
+    Pop = 15,           // Discard the top of the stack
+    DefineGlobal = 16,  // var x = ...; at the top level
+    GetGlobal = 17,     // Read a global variable
+    SetGlobal = 18,     // Assign to an existing global variable
+    GetLocal = 19,      // Read a local variable from its stack slot
+    SetLocal = 20,      // Write a local variable into its stack slot
+    Jump = 21,          // Unconditionally jump forward by a 16-bit offset
+    JumpIfFalse = 22,   // Jump forward if the top of the stack is falsy (does not pop it)
+    Loop = 23,          // Unconditionally jump backward by a 16-bit offset
+    Call = 24,          // Call the value below the arguments, operand is the argument count
+    GetUpvalue = 25,    // Read a variable captured by the running closure
+    SetUpvalue = 26,    // Write a variable captured by the running closure
+    Closure = 27,       // Wrap a function constant into a closure and capture its upvalues
+    CloseUpvalue = 28,  // Move a captured local off the stack before popping it
+    Class = 29,         // Create a new class, operand is the name constant
+    GetProperty = 30,   // instance.name
+    SetProperty = 31,   // instance.name = value
+    Method = 32,        // Add the closure on top of the stack as a method of the class below it
+    Invoke = 33,        // instance.name(args), operands are the name constant and the argument count
+    Inherit = 34,       // Copy the methods of the superclass into the subclass
+    GetSuper = 35,      // super.name
+    SuperInvoke = 36,   // super.name(args), same operands as Invoke
+    BuildList = 37,     // [a, b, c], the operand is the number of items on the stack
+    IndexGet = 38,      // list[index]
+    IndexSet = 39,      // list[index] = value
+    BuildMap = 40,      // {k: v, ...}, the operand is the number of key/value pairs on the stack
+    ToString = 41,      // Replace the value on top of the stack with its string form, used by "${...}"
+    Throw = 42,         // Throw the value on top of the stack
+    EndFinally = 43,    // End of a finally block, resume the return or exception it interrupted
+    Import = 44,        // Push the module at the path constant, running its top-level code the first time
+    ImportName = 45,    // Push the exported variable named by the constant from the module on top of the stack
+    Modulo = 46,        // a % b
+    BitAnd = 47,        // a & b
+    BitOr = 48,         // a | b
+    BitXor = 49,        // a ^ b
+    BitNot = 50,        // ~a
+    ShiftLeft = 51,     // a << b
+    ShiftRight = 52,    // a >> b
+    GetIter = 53,       // Replace the value on top of the stack with an iterator over it
+    IterNext = 54,      // Push the next item of the iterator on top of the stack, or `done` when it is done
+    IterCheck = 55,     // Leave the for-in loop by a 16-bit offset if a user iterator's next() returned `done`
+    Yield = 56,         // Suspend the running generator and hand the value on top of the stack to next()'s caller
+    MatchLen = 57,      // Replace the value on top of the stack with whether it is a list of exactly N items
+    MatchRange = 58,    // [value][low][high] -> whether value is a number between low and high, both included
+    JumpTable = 59,     // Jump backward to the match arm for the int on top of the stack, see Compiler::emit_jump_table
+    NoMatch = 60,       // Runtime error: no match arm accepts the value on top of the stack
+    CallNamed = 61,     // Like Call, but the last arguments are named: arg count, named count, then a name constant per named argument
+    DefineConst = 62,   // Like DefineGlobal, and the global can't be assigned or defined again
+    JumpIfGiven = 63,   // Skip a parameter's default value if the call gave it an argument: the slot, then a 16-bit offset
+    Shuffle = 64,       // Put the arguments in parameter order: arg count, parameter count, then per parameter the argument it gets
 }
 
 impl TryFrom<u8> for OpCode {
@@ -35,6 +86,57 @@ impl TryFrom<u8> for OpCode {
             11 => Ok(OpCode::Equal),
             12 => Ok(OpCode::Greater),
             13 => Ok(OpCode::Less),
+            14 => Ok(OpCode::Print),
+            15 => Ok(OpCode::Pop),
+            16 => Ok(OpCode::DefineGlobal),
+            17 => Ok(OpCode::GetGlobal),
+            18 => Ok(OpCode::SetGlobal),
+            19 => Ok(OpCode::GetLocal),
+            20 => Ok(OpCode::SetLocal),
+            21 => Ok(OpCode::Jump),
+            22 => Ok(OpCode::JumpIfFalse),
+            23 => Ok(OpCode::Loop),
+            24 => Ok(OpCode::Call),
+            25 => Ok(OpCode::GetUpvalue),
+            26 => Ok(OpCode::SetUpvalue),
+            27 => Ok(OpCode::Closure),
+            28 => Ok(OpCode::CloseUpvalue),
+            29 => Ok(OpCode::Class),
+            30 => Ok(OpCode::GetProperty),
+            31 => Ok(OpCode::SetProperty),
+            32 => Ok(OpCode::Method),
+            33 => Ok(OpCode::Invoke),
+            34 => Ok(OpCode::Inherit),
+            35 => Ok(OpCode::GetSuper),
+            36 => Ok(OpCode::SuperInvoke),
+            37 => Ok(OpCode::BuildList),
+            38 => Ok(OpCode::IndexGet),
+            39 => Ok(OpCode::IndexSet),
+            40 => Ok(OpCode::BuildMap),
+            41 => Ok(OpCode::ToString),
+            42 => Ok(OpCode::Throw),
+            43 => Ok(OpCode::EndFinally),
+            44 => Ok(OpCode::Import),
+            45 => Ok(OpCode::ImportName),
+            46 => Ok(OpCode::Modulo),
+            47 => Ok(OpCode::BitAnd),
+            48 => Ok(OpCode::BitOr),
+            49 => Ok(OpCode::BitXor),
+            50 => Ok(OpCode::BitNot),
+            51 => Ok(OpCode::ShiftLeft),
+            52 => Ok(OpCode::ShiftRight),
+            53 => Ok(OpCode::GetIter),
+            54 => Ok(OpCode::IterNext),
+            55 => Ok(OpCode::IterCheck),
+            56 => Ok(OpCode::Yield),
+            57 => Ok(OpCode::MatchLen),
+            58 => Ok(OpCode::MatchRange),
+            59 => Ok(OpCode::JumpTable),
+            60 => Ok(OpCode::NoMatch),
+            61 => Ok(OpCode::CallNamed),
+            62 => Ok(OpCode::DefineConst),
+            63 => Ok(OpCode::JumpIfGiven),
+            64 => Ok(OpCode::Shuffle),
             _ => Err(format!("Unknown opcode: {}", byte)),
         }
     }
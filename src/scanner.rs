@@ -3,23 +3,33 @@ pub enum TokenType {
     // Single-character tokens
     LeftParen, RightParen,
     LeftBrace, RightBrace,
-    Comma, Dot, Minus, Plus,
+    LeftBracket, RightBracket,
+    Comma, Colon, Dot, Minus, Plus,
     Semicolon, Slash, Star,
+    Percent, Ampersand, Pipe, Caret, Tilde,
     
     // One or two character tokens
     Bang, BangEqual,
-    Equal, EqualEqual,
+    Equal, EqualEqual, Arrow,
     Greater, GreaterEqual,
-    Less, LessEqual,
+    Less, LessEqual, LessLess,
+    GreaterGreater, DotDot, DotDotDot,
     
     // Literals
-    Identifier, String, Number,
+    // The lexeme of a String or Interpolation token is the text between the delimiters, escapes already replaced:
+    /*
+        "Hello ${name}, you are ${age + 1}!"
+
+        Interpolation("Hello ") Identifier(name) Interpolation(", you are ")
+        Identifier(age) Plus Number(1) String("!")
+    */
+    Identifier, String, Interpolation, Number,
     
     // Keywords
-    And, Class, Else, False,
-    For, Fun, If, Nil, Or,
+    And, Catch, Class, Const, Else, Export, False,
+    Finally, For, Fun, If, Import, Match, Nil, Or,
     Print, Return, Super, This,
-    True, Var, While,
+    Throw, True, Try, Var, While, Yield,
     
     Error, Eof
 }
@@ -31,6 +41,14 @@ pub struct Token {
     pub line: usize,
 }
 
+// Where the scanner is, saved so it can scan ahead and come back.
+pub struct Checkpoint {
+    start: usize,
+    current: usize,
+    line: usize,
+    interpolation_depths: Vec<usize>,
+}
+
 pub struct Scanner {
     // source: String,
     chars: Vec<char>, // The vector of source code's character
@@ -38,6 +56,9 @@ pub struct Scanner {
     // Example: When scanning "print", start points to 'p'
     current: usize, // Current position in the source
     line: usize, // Current line number -> For error reporting
+    // One entry per `${` we are inside of, counting the `{` opened since then,
+    // so we know which `}` ends the interpolated expression and goes back to the string.
+    interpolation_depths: Vec<usize>,
 }
 
 impl Scanner {
@@ -49,9 +70,37 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            interpolation_depths: Vec::new(),
         }
     }
     
+    // The token scan_token would return next, without consuming it. The compiler already holds the
+    // current token, so this is a second token of lookahead: `for (x in xs)` and `for (x = 0; ...)`
+    // only differ there.
+    pub fn peek_token(&mut self) -> Token {
+        let checkpoint = self.checkpoint();
+        let token = self.scan_token();
+        self.restore(checkpoint);
+        token
+    }
+
+    // For lookahead further than one token: scan as far as needed, then restore.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            start: self.start,
+            current: self.current,
+            line: self.line,
+            interpolation_depths: self.interpolation_depths.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, checkpoint: Checkpoint) {
+        self.start = checkpoint.start;
+        self.current = checkpoint.current;
+        self.line = checkpoint.line;
+        self.interpolation_depths = checkpoint.interpolation_depths;
+    }
+
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
         self.start = self.current;
@@ -66,15 +115,49 @@ impl Scanner {
             // Single character tokens
             '(' => self.make_token(TokenType::LeftParen),
             ')' => self.make_token(TokenType::RightParen),
-            '{' => self.make_token(TokenType::LeftBrace),
-            '}' => self.make_token(TokenType::RightBrace),
+            '{' => {
+                if let Some(depth) = self.interpolation_depths.last_mut() {
+                    *depth += 1;
+                }
+                self.make_token(TokenType::LeftBrace)
+            },
+            '}' => match self.interpolation_depths.last_mut() {
+                Some(0) => {
+                    // The end of `${...}`, the rest is still part of the string.
+                    self.interpolation_depths.pop();
+                    self.string()
+                },
+                Some(depth) => {
+                    *depth -= 1;
+                    self.make_token(TokenType::RightBrace)
+                },
+                None => self.make_token(TokenType::RightBrace),
+            },
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
             ';' => self.make_token(TokenType::Semicolon),
             ',' => self.make_token(TokenType::Comma),
-            '.' => self.make_token(TokenType::Dot),
+            ':' => self.make_token(TokenType::Colon),
+            '.' => {
+                if self.match_char('.') {
+                    if self.match_char('.') {
+                        self.make_token(TokenType::DotDotDot)
+                    } else {
+                        self.make_token(TokenType::DotDot)
+                    }
+                } else {
+                    self.make_token(TokenType::Dot)
+                }
+            },
             '-' => self.make_token(TokenType::Minus),
             '+' => self.make_token(TokenType::Plus),
             '/' => self.make_token(TokenType::Slash),
             '*' => self.make_token(TokenType::Star),
+            '%' => self.make_token(TokenType::Percent),
+            '&' => self.make_token(TokenType::Ampersand),
+            '|' => self.make_token(TokenType::Pipe),
+            '^' => self.make_token(TokenType::Caret),
+            '~' => self.make_token(TokenType::Tilde),
             
             // Two character tokens
             '!' => {
@@ -87,6 +170,8 @@ impl Scanner {
             '=' => {
                 if self.match_char('=') {
                     self.make_token(TokenType::EqualEqual)
+                } else if self.match_char('>') {
+                    self.make_token(TokenType::Arrow)
                 } else {
                     self.make_token(TokenType::Equal)
                 }
@@ -94,6 +179,8 @@ impl Scanner {
             '<' => {
                 if self.match_char('=') {
                     self.make_token(TokenType::LessEqual)
+                } else if self.match_char('<') {
+                    self.make_token(TokenType::LessLess)
                 } else {
                     self.make_token(TokenType::Less)
                 }
@@ -101,6 +188,8 @@ impl Scanner {
             '>' => {
                 if self.match_char('=') {
                     self.make_token(TokenType::GreaterEqual)
+                } else if self.match_char('>') {
+                    self.make_token(TokenType::GreaterGreater)
                 } else {
                     self.make_token(TokenType::Greater)
                 }
@@ -196,21 +285,68 @@ impl Scanner {
         }
     }
     
+    // Scan the rest of a string, up to the closing quote or the next `${`.
     fn string(&mut self) -> Token {
-        while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+        let mut chars = String::new();
+        // An invalid escape is only reported once we reach the end of the string (or of this piece of it),
+        // otherwise the rest of the literal would be scanned as code and cause a cascade of bogus errors.
+        let mut invalid_escape_line = None;
+        loop {
+            if self.is_at_end() {
+                return self.error_token("Unterminated string.");
+            }
+            match self.advance() {
+                '"' => break,
+                '$' if self.peek() == '{' => {
+                    self.advance();
+                    self.interpolation_depths.push(0);
+                    if invalid_escape_line.is_none() {
+                        return self.make_string_token(TokenType::Interpolation, chars);
+                    }
+                    break;
+                },
+                '\\' => {
+                    let escaped = match self.peek() {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        '"' => '"',
+                        '\\' => '\\',
+                        '$' => '$', // "\${" is a literal "${"
+                        _ => {
+                            invalid_escape_line.get_or_insert(self.line);
+                            // Leave the character alone, it may be a newline that still has to be counted.
+                            continue;
+                        },
+                    };
+                    self.advance();
+                    chars.push(escaped);
+                },
+                c => {
+                    if c == '\n' {
+                        self.line += 1;
+                    }
+                    chars.push(c);
+                },
             }
-            self.advance();
         }
-        
-        if self.is_at_end() {
-            return self.error_token("Unterminated string.");
+
+        match invalid_escape_line {
+            Some(line) => Token {
+                token_type: TokenType::Error,
+                lexeme: "Invalid escape sequence.".to_string(),
+                line,
+            },
+            None => self.make_string_token(TokenType::String, chars),
+        }
+    }
+
+    fn make_string_token(&self, token_type: TokenType, chars: String) -> Token {
+        Token {
+            token_type,
+            lexeme: chars,
+            line: self.line,
         }
-        
-        // Consume the closing quote
-        self.advance();
-        self.make_token(TokenType::String)
     }
     
     fn number(&mut self) -> Token {
@@ -248,12 +384,34 @@ impl Scanner {
         
         match self.chars[self.start] {
             'a' => self.check_keyword(1, "nd", TokenType::And),
-            'c' => self.check_keyword(1, "lass", TokenType::Class),
-            'e' => self.check_keyword(1, "lse", TokenType::Else),
+            'c' => {
+                if self.current - self.start > 1 {
+                    match self.chars[self.start + 1] {
+                        'a' => self.check_keyword(2, "tch", TokenType::Catch),
+                        'l' => self.check_keyword(2, "ass", TokenType::Class),
+                        'o' => self.check_keyword(2, "nst", TokenType::Const),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            },
+            'e' => {
+                if self.current - self.start > 1 {
+                    match self.chars[self.start + 1] {
+                        'l' => self.check_keyword(2, "se", TokenType::Else),
+                        'x' => self.check_keyword(2, "port", TokenType::Export),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            },
             'f' => {
                 if self.current - self.start > 1 {
                     match self.chars[self.start + 1] {
                         'a' => self.check_keyword(2, "lse", TokenType::False),
+                        'i' => self.check_keyword(2, "nally", TokenType::Finally),
                         'o' => self.check_keyword(2, "r", TokenType::For),
                         'u' => self.check_keyword(2, "n", TokenType::Fun),
                         _ => TokenType::Identifier,
@@ -262,7 +420,18 @@ impl Scanner {
                     TokenType::Identifier
                 }
             },
-            'i' => self.check_keyword(1, "f", TokenType::If),
+            'i' => {
+                if self.current - self.start > 1 {
+                    match self.chars[self.start + 1] {
+                        'f' => self.check_keyword(2, "", TokenType::If),
+                        'm' => self.check_keyword(2, "port", TokenType::Import),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            },
+            'm' => self.check_keyword(1, "atch", TokenType::Match),
             'n' => self.check_keyword(1, "il", TokenType::Nil),
             'o' => self.check_keyword(1, "r", TokenType::Or),
             'p' => self.check_keyword(1, "rint", TokenType::Print),
@@ -271,8 +440,16 @@ impl Scanner {
             't' => {
                 if self.current - self.start > 1 {
                     match self.chars[self.start + 1] {
-                        'h' => self.check_keyword(2, "is", TokenType::This),
-                        'r' => self.check_keyword(2, "ue", TokenType::True),
+                        'h' if self.current - self.start > 2 => match self.chars[self.start + 2] {
+                            'i' => self.check_keyword(3, "s", TokenType::This),
+                            'r' => self.check_keyword(3, "ow", TokenType::Throw),
+                            _ => TokenType::Identifier,
+                        },
+                        'r' if self.current - self.start > 2 => match self.chars[self.start + 2] {
+                            'u' => self.check_keyword(3, "e", TokenType::True),
+                            'y' => self.check_keyword(3, "", TokenType::Try),
+                            _ => TokenType::Identifier,
+                        },
                         _ => TokenType::Identifier,
                     }
                 } else {
@@ -281,6 +458,7 @@ impl Scanner {
             },
             'v' => self.check_keyword(1, "ar", TokenType::Var),
             'w' => self.check_keyword(1, "hile", TokenType::While),
+            'y' => self.check_keyword(1, "ield", TokenType::Yield),
             _ => TokenType::Identifier,
         }
     }
@@ -0,0 +1,228 @@
+use crate::common::Value;
+use crate::number;
+use crate::object::ObjRef;
+
+// Grow the table once it is 75% full, like clox does.
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    key: Option<Value>, // None for empty entries and tombstones
+    value: Value,
+}
+
+impl Entry {
+    fn empty() -> Self {
+        Self { key: None, value: Value::Nil }
+    }
+
+    // A deleted entry keeps probing sequences intact, so we mark it with
+    // a "tombstone" (no key, value true) instead of making it truly empty.
+    fn is_tombstone(&self) -> bool {
+        self.key.is_none() && !matches!(self.value, Value::Nil)
+    }
+}
+
+// A hash table using open addressing with linear probing.
+/*
+    key "apple"  -> hash % capacity = 2
+    key "banana" -> hash % capacity = 2 (collision!) -> try 3
+
+    Index | Key      | Value
+    ------|----------|------
+      0   |          |
+      1   |          |
+      2   | "apple"  | 1
+      3   | "banana" | 2
+*/
+// Keys are values (see Value::is_hashable). Strings are interned, so comparing two string keys
+// is just comparing two handles, and numbers compare by value like they do in Lox.
+// The globals, fields, methods, the string intern table and Lox maps all use this table.
+pub struct Table {
+    count: usize, // Number of live entries + tombstones
+    entries: Vec<Entry>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self {
+            count: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, key: Value) -> Option<Value> {
+        if self.count == 0 {
+            return None;
+        }
+        let entry = &self.entries[Self::find_entry(&self.entries, key)];
+        entry.key.map(|_| entry.value)
+    }
+
+    // Returns true if the key was not in the table before.
+    pub fn set(&mut self, key: Value, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.entries.len() as f64 * TABLE_MAX_LOAD {
+            let capacity = if self.entries.len() < 8 { 8 } else { self.entries.len() * 2 };
+            self.adjust_capacity(capacity);
+        }
+
+        let index = Self::find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        let is_new_key = entry.key.is_none();
+        // Reusing a tombstone does not change the count, it was already counted.
+        if is_new_key && !entry.is_tombstone() {
+            self.count += 1;
+        }
+        entry.key = Some(key);
+        entry.value = value;
+        is_new_key
+    }
+
+    pub fn delete(&mut self, key: Value) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+        let index = Self::find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        if entry.key.is_none() {
+            return false;
+        }
+        entry.key = None;
+        entry.value = Value::Bool(true);
+        true
+    }
+
+    // Look a string up by its content. This is how strings get interned:
+    // before allocating a new string, we check whether an equal one already exists.
+    pub fn find_string(&self, chars: &str, hash: u32) -> Option<ObjRef> {
+        if self.count == 0 {
+            return None;
+        }
+        let capacity = self.entries.len();
+        let mut index = hash as usize % capacity;
+        loop {
+            let entry = &self.entries[index];
+            match entry.key {
+                None if !entry.is_tombstone() => return None,
+                Some(Value::Obj(key)) => {
+                    let string = key.as_string().expect("The intern table only holds strings");
+                    if string.hash == hash && string.chars == chars {
+                        return Some(key);
+                    }
+                }
+                Some(_) => {}
+                None => {}
+            }
+            index = (index + 1) % capacity;
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Number of live entries, unlike `count` this does not include tombstones.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    // Copy every entry of this table into another one.
+    pub fn add_all(&self, to: &mut Table) {
+        for (key, value) in self.iter() {
+            to.set(key, value);
+        }
+    }
+
+    // The first live entry at or after `slot`, with the slot it is in, so iteration can resume from the next one.
+    pub fn next_entry(&self, slot: usize) -> Option<(usize, Value, Value)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .skip(slot)
+            .find_map(|(index, entry)| entry.key.map(|key| (index, key, entry.value)))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Value, Value)> + '_ {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.key.map(|key| (key, entry.value)))
+    }
+
+    // Used by the garbage collector on the string table: drop every string that is about to be freed.
+    pub fn remove_unmarked(&mut self) {
+        let unmarked: Vec<Value> = self
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| matches!(key, Value::Obj(string) if !string.is_marked()))
+            .collect();
+        for key in unmarked {
+            self.delete(key);
+        }
+    }
+
+    fn find_entry(entries: &[Entry], key: Value) -> usize {
+        let capacity = entries.len();
+        let hash = hash_value(key);
+        let mut index = hash as usize % capacity;
+        let mut tombstone = None;
+        loop {
+            let entry = &entries[index];
+            match entry.key {
+                Some(k) if k == key => return index,
+                Some(_) => {}
+                None => {
+                    if !entry.is_tombstone() {
+                        // Prefer handing back a tombstone we passed so it can be reused.
+                        return tombstone.unwrap_or(index);
+                    }
+                    if tombstone.is_none() {
+                        tombstone = Some(index);
+                    }
+                }
+            }
+            index = (index + 1) % capacity;
+        }
+    }
+
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let old_entries = std::mem::replace(&mut self.entries, vec![Entry::empty(); capacity]);
+        // Tombstones are dropped while rehashing, so recount from zero.
+        self.count = 0;
+        for entry in old_entries {
+            if let Some(key) = entry.key {
+                let index = Self::find_entry(&self.entries, key);
+                self.entries[index] = entry;
+                self.count += 1;
+            }
+        }
+    }
+}
+
+// Equal values must hash the same: 1 and 1.0 are equal, and so are 0.0 and -0.0.
+fn hash_value(value: Value) -> u32 {
+    match value {
+        Value::Int(n) => hash_int(n),
+        Value::Number(n) => match number::float_to_int(n) {
+            Some(n) => hash_int(n),
+            None => {
+                let bits = n.to_bits();
+                (bits ^ (bits >> 32)) as u32
+            }
+        },
+        Value::Bool(true) => 3,
+        Value::Bool(false) => 5,
+        Value::Nil => 7,
+        Value::Obj(object) => object.as_string().expect("Only hashable values can be table keys").hash,
+    }
+}
+
+fn hash_int(n: i64) -> u32 {
+    let bits = n as u64;
+    (bits ^ (bits >> 32)) as u32
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Self::new()
+    }
+}
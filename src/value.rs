@@ -42,10 +42,6 @@ impl ValueArray {
         self.values.len()
     }
 
-    pub fn capacity(&self) -> usize {
-        self.values.capacity()
-    }
-
     pub fn get(&self, index: usize) -> Option<&Value> {
         self.values.get(index)
     }
@@ -63,9 +59,4 @@ impl Default for ValueArray {
 
 pub fn print_value(value: &Value) {
     println!("{}", value);
-}
-
-// Helper function for formatting values in disassembly
-pub fn value_to_string(value: Value) -> String {
-    format!("{}", value)
 }
\ No newline at end of file
@@ -1,15 +1,83 @@
-use crate::{chunk::Chunk, opcode::OpCode};
-use crate::common::Value;
+use crate::chunk::{Chunk, ExceptionHandler, COMPLETION_RETURN, COMPLETION_THROW, NOT_GIVEN};
+use crate::opcode::OpCode;
+use crate::common::{Value, DEBUG_TRACE_EXECUTION};
+use crate::compiler;
+use crate::memory::Heap;
+use crate::native;
+use crate::number::{self, Arithmetic, Computed};
+use crate::bigint::BigInt;
+use crate::object::{
+    FiberState, GeneratorState, Iterable, NativeFn, Obj, ObjBigInt, ObjBoundMethod, ObjChannel, ObjFiber, ObjGenerator, ObjIterator, ObjClass, ObjClosure, ObjInstance, ObjList, ObjMap, ObjModule, ObjNative, ObjRef, ObjUpvalue, UpvalueState,
+};
+use crate::table::Table;
 use crate::value::print_value;
 use crate::debug::{disassemble_instruction};
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::fmt::Display;
+use std::fs;
+use std::collections::VecDeque;
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
 
-const STACK_MAX: usize = 256;
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
+
+// Lox code run by every new VM, for the parts of the runtime that are easier to write in Lox itself.
+// Runtime errors raised by the VM are instances of Error too, with `message` and `line` fields.
+const PRELUDE: &str = r#"
+class Error {
+    init(message) {
+        this.message = message;
+    }
+}
+
+// What next() returns once an iterator has nothing left, so that nil can be an item like any other.
+class Done {}
+var done = Done();
+"#;
+
+// One ongoing function call.
+/*
+    fun add(a, b) { return a + b; }
+    print add(1, 2);
+
+    stack: [ <script> ][ <fn add> ][ 1 ][ 2 ]
+                       ^
+                       slots: the callee and its locals start here,
+                       so local slot 1 (a) is stack[slots + 1]
+*/
+pub(crate) struct CallFrame {
+    pub(crate) closure: ObjRef,
+    pub(crate) ip: usize,  // instruction pointer (or it is called Program Counter) - index into function's chunk.code()
+    pub(crate) slots: usize, // Index of the first stack slot this call can use
+    pub(crate) generator: Option<ObjRef>, // The generator this call belongs to, if it is one
+}
 
 pub struct VM {
-    chunk: Option<Chunk>,
-    ip: usize,  // instruction pointer (or it is called Program Counter) - index into chunk.code()
-    stack: Vec<Value>,
+    frames: Vec<CallFrame>, // The call stack of the running fiber, the running function is last
+    stack: Vec<Value>, // The value stack of the running fiber
+    heap: Heap, // Owns every object created while running, e.g. strings
+    builtins: Table, // Natives and what the prelude defines, visible from every module
+    main_module: ObjRef, // Survives between calls to interpret(), so the REPL remembers variables
+    modules: Table, // Every module loaded so far by canonical path, so that each one only runs once
+    search_paths: Vec<PathBuf>, // Where imports are looked for when they are not next to the importing file
+    open_upvalues: Vec<ObjRef>, // Upvalues still pointing into the stack, sorted by stack slot
+    main_fiber: ObjRef, // Runs the script, the other fibers come from spawn()
+    current_fiber: ObjRef, // Whose stack, frames and upvalues are the ones above
+    ready: VecDeque<ObjRef>, // Fibers waiting for their turn, the scheduler runs them in order
+    blocked_fibers: usize, // How many fibers wait on a channel, any left when the script ends are deadlocked
+    pause_requested: bool, // Set by the pause() native, the switch happens once the native returns
+    instruction_start: usize, // Where the running instruction begins, a fiber blocked by it runs it again from there
+    compiler_roots: Vec<Value>, // Constants of functions the compiler is still working on
+    init_string: ObjRef, // "init", looked up every time a class is called
+    iterator_string: ObjRef, // "iterator" and "next", the methods of the iterator protocol
+    next_string: ObjRef,
+    error_class: Option<ObjRef>, // The prelude's Error class, None only while the prelude itself runs
+    no_argument: ObjRef, // Stands in for a left out argument until the callee evaluates the default value
+    done: Option<ObjRef>, // The prelude's `done`, the end of an iteration
 }
 
 #[derive(Debug, PartialEq)]
@@ -22,173 +90,1861 @@ pub enum InterpretResult {
 impl VM {
 
     pub fn new() -> Self {
-        Self {
-            chunk: None,
-            ip: 0,
+        let mut heap = Heap::new();
+        let init_string = heap.alloc_string("init".to_string());
+        let iterator_string = heap.alloc_string("iterator".to_string());
+        let next_string = heap.alloc_string("next".to_string());
+        // A class of its own that Lox code can never get hold of, so no argument can be mistaken for it.
+        let no_argument_name = heap.alloc_string("<no argument>".to_string());
+        let no_argument = heap.alloc(Obj::Class(ObjClass::new(no_argument_name)));
+        let main_name = heap.alloc_string("<script>".to_string());
+        let main_module = heap.alloc(Obj::Module(ObjModule::new(main_name, PathBuf::from("."))));
+        let main_fiber = heap.alloc(Obj::Fiber(ObjFiber::new(Vec::new(), Vec::new(), FiberState::Running)));
+        let mut vm = Self {
+            frames: Vec::new(),
             stack: Vec::new(),
-        }
+            heap,
+            builtins: Table::new(),
+            main_module,
+            modules: Table::new(),
+            search_paths: Vec::new(),
+            open_upvalues: Vec::new(),
+            main_fiber,
+            current_fiber: main_fiber,
+            ready: VecDeque::new(),
+            blocked_fibers: 0,
+            pause_requested: false,
+            instruction_start: 0,
+            compiler_roots: Vec::new(),
+            init_string,
+            iterator_string,
+            next_string,
+            error_class: None,
+            no_argument,
+            done: None,
+        };
+        native::define_natives(&mut vm);
+        vm.run_prelude();
+        vm
+    }
+
+    // The prelude runs as a module of its own, then everything it defined becomes a builtin.
+    fn run_prelude(&mut self) {
+        let name = self.alloc_string("<prelude>".to_string());
+        self.stack.push(Value::Obj(name));
+        let prelude = self.alloc(Obj::Module(ObjModule::new(name, PathBuf::from("."))));
+        // Still on the stack while run_module() compiles, it resets the stack only afterwards.
+        self.stack.push(Value::Obj(prelude));
+
+        let result = self.run_module(PRELUDE.to_string(), prelude).expect("The prelude does not throw");
+        assert_eq!(result, InterpretResult::Ok, "The prelude compiles");
+        prelude.as_module().expect("Just allocated").globals.borrow().add_all(&mut self.builtins);
+
+        let error_name = self.alloc_string("Error".to_string());
+        self.error_class = match self.builtins.get(Value::Obj(error_name)) {
+            Some(Value::Obj(class)) => Some(class),
+            _ => unreachable!("The prelude defines Error"),
+        };
+        let done_name = self.alloc_string("done".to_string());
+        self.done = match self.builtins.get(Value::Obj(done_name)) {
+            Some(Value::Obj(done)) => Some(done),
+            _ => unreachable!("The prelude defines done"),
+        };
     }
 
-    pub fn interpret(&mut self, chunk: Chunk) -> Result<InterpretResult> {
-        self.chunk = Some(chunk);
-        self.ip = 0;
+    // Imports in the main script are resolved relative to the script's directory.
+    pub fn set_script_path(&mut self, path: &str) {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        let directory = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let name = self.alloc_string(path.display().to_string());
+        self.stack.push(Value::Obj(name));
+        self.main_module = self.alloc(Obj::Module(ObjModule::new(name, directory)));
+        self.stack.pop();
+        // Cached like any other module, so a module importing the main script is reported as a cycle.
+        self.modules.set(Value::Obj(name), Value::Obj(self.main_module));
+    }
+
+    pub fn add_search_path(&mut self, path: PathBuf) {
+        self.search_paths.push(path);
+    }
+
+    // Make a Rust function callable from Lox as the global `name`.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        // Both objects sit on the stack while the other one is allocated, so a collection can't free them.
+        let name = self.alloc_string(name.to_string());
+        self.stack.push(Value::Obj(name));
+        let native = self.alloc(Obj::Native(ObjNative { name, arity, function }));
+        self.stack.push(Value::Obj(native));
+        self.builtins.set(Value::Obj(name), Value::Obj(native));
+        self.stack.pop();
+        self.stack.pop();
+    }
+
+    pub fn set_stress_gc(&mut self, stress_gc: bool) {
+        self.heap.stress_gc = stress_gc;
+    }
+
+    pub fn set_log_gc(&mut self, log_gc: bool) {
+        self.heap.log_gc = log_gc;
+    }
+
+    pub fn interpret(&mut self, source: String) -> Result<InterpretResult> {
+        self.run_module(source, self.main_module)
+    }
+
+    fn run_module(&mut self, source: String, module: ObjRef) -> Result<InterpretResult> {
+        let function = compiler::compile(source, self, module);
+        // Everything the compiler produced is now reachable from the script function.
+        self.compiler_roots.clear();
+        let function = match function {
+            Some(function) => function,
+            None => return Ok(InterpretResult::CompileError),
+        };
         self.reset_stack();
+        // The top-level script is called like any other function with zero arguments.
+        self.push(Value::Obj(function))?;
+        let closure = self.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.pop()?;
+        self.push(Value::Obj(closure))?;
+        self.call(closure, 0)?;
         self.run()
     }
     
     fn run(&mut self) -> Result<InterpretResult> {
         loop {
+            match self.step() {
+                Ok(None) => {}
+                Ok(Some(result)) => return Ok(result),
+                // throw() already reported an uncaught exception and reset the VM, nothing left to run.
+                Err(err) if self.frames.is_empty() => return Err(err),
+                // Every other runtime error becomes an Error instance that the script can catch.
+                Err(err) => {
+                    let error = self.make_error(err);
+                    self.throw(error)?;
+                }
+            }
+        }
+    }
 
+    // Execute one instruction. Some(result) once the script has finished.
+    fn step(&mut self) -> Result<Option<InterpretResult>> {
+        if DEBUG_TRACE_EXECUTION {
             self.debug_trace_execution();
+        }
+
+        self.instruction_start = self.frame().ip;
+        let instruction: u8 = self.read_byte();
+        match OpCode::try_from(instruction) {
+            Ok(opcode) => match opcode {
+                OpCode::Constant => {
+                    let constant = self.read_constant();
+                    self.push(constant)?;
+                }
+                OpCode:: True => {
+                    self.push(Value::Bool(true))?;
+                }
+                OpCode:: False => {
+                    self.push(Value::Bool(false))?;
+                }
+                OpCode:: Nil => {
+                    self.push(Value::Nil)?;
+                }
+                OpCode::Print => {
+                    let value = self.pop()?;
+                    print_value(&value);
+                }
+                OpCode::Pop => {
+                    self.pop()?;
+                }
+                OpCode::DefineGlobal => {
+                    let name = self.read_string();
+                    self.check_not_const(name, "redefine")?;
+                    // Peek instead of pop so the value stays reachable while we insert it.
+                    let value = self.peek(0);
+                    self.globals().borrow_mut().set(Value::Obj(name), value);
+                    self.remeasure(self.current_module());
+                    self.pop()?;
+                }
+                OpCode::JumpIfGiven => {
+                    let slot = self.read_byte() as usize;
+                    let offset = self.read_short();
+                    let argument = self.stack[self.frame().slots + slot];
+                    if !matches!(argument, Value::Obj(object) if object == self.no_argument) {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::DefineConst => {
+                    let name = self.read_string();
+                    self.check_not_const(name, "redefine")?;
+                    let value = self.peek(0);
+                    self.globals().borrow_mut().set(Value::Obj(name), value);
+                    self.const_globals().borrow_mut().set(Value::Obj(name), Value::Bool(true));
+                    self.remeasure(self.current_module());
+                    self.pop()?;
+                }
+                OpCode::GetGlobal => {
+                    let name = self.read_string();
+                    // The module's own globals shadow the builtins.
+                    let value = self.globals().borrow().get(Value::Obj(name));
+                    match value.or_else(|| self.builtins.get(Value::Obj(name))) {
+                        Some(value) => self.push(value)?,
+                        None => return Err(self.runtime_error(format!("Undefined variable '{}'.", *name))),
+                    }
+                }
+                OpCode::SetGlobal => {
+                    let name = self.read_string();
+                    // The compiler catches this too, unless the constant is declared after the assignment
+                    // or in an earlier line of the REPL.
+                    self.check_not_const(name, "assign to")?;
+                    let value = self.peek(0);
+                    // Assignment is not declaration: setting a variable that does not exist is an error.
+                    let is_new = self.globals().borrow_mut().set(Value::Obj(name), value);
+                    if is_new {
+                        self.globals().borrow_mut().delete(Value::Obj(name));
+                        return Err(self.runtime_error(format!("Undefined variable '{}'.", *name)));
+                    }
+                    // No pop here, assignment is an expression and leaves its value on the stack.
+                }
+                OpCode::GetLocal => {
+                    // Locals live directly in their stack slot, no name lookup needed.
+                    let slot = self.read_byte() as usize;
+                    self.push(self.stack[self.frame().slots + slot])?;
+                }
+                OpCode::SetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let base = self.frame().slots;
+                    self.stack[base + slot] = self.peek(0);
+                }
+                OpCode::Jump => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset as usize;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short();
+                    if self.peek(0).is_falsy() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset as usize;
+                }
+                OpCode::GetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.current_closure().upvalues[slot];
+                    let value = match self.upvalue_state(upvalue) {
+                        UpvalueState::Open(index) => self.stack[index],
+                        UpvalueState::Suspended(owner, index) => owner.saved_stack().borrow()[index],
+                        UpvalueState::Closed(value) => value,
+                    };
+                    self.push(value)?;
+                }
+                OpCode::SetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.current_closure().upvalues[slot];
+                    let value = self.peek(0);
+                    match self.upvalue_state(upvalue) {
+                        UpvalueState::Open(index) => self.stack[index] = value,
+                        UpvalueState::Suspended(owner, index) => owner.saved_stack().borrow_mut()[index] = value,
+                        UpvalueState::Closed(_) => Self::set_upvalue_state(upvalue, UpvalueState::Closed(value)),
+                    }
+                }
+                OpCode::Closure => {
+                    let function = match self.read_constant() {
+                        Value::Obj(function) => function,
+                        _ => unreachable!("OP_CLOSURE always refers to a function constant"),
+                    };
+                    let function_ref = function.as_function().expect("OP_CLOSURE needs a function");
+                    let upvalue_count = function_ref.upvalue_count;
 
-            // TODO: Read the next instruction
-            let instruction: u8 = self.read_byte();
-            // TODO: Match on the opcode and execute it
-            match OpCode::try_from(instruction) {
-                Ok(opcode) => match opcode {
-                    OpCode::Constant => {
-                        let constant = self.read_constant();
-                        println!("Loading constant: {}", constant);
-                        self.push(constant)?;
-                    }
-                    OpCode:: True => {
-                        print_value(&Value::Bool(true));
-                        self.push(Value::Bool(true))?;
-                    }
-                    OpCode:: False => {
-                        print_value(&Value::Bool(false));
-                        self.push(Value::Bool(false))?;
-                    }
-                    OpCode:: Nil => {
-                        print_value(&Value::Nil);
-                        self.push(Value::Nil)?;
-                    }
-                    OpCode::Return => {
-                        let value = self.pop()?;
-                        print_value(&value);
-                        println!();
-                        return Ok(InterpretResult::Ok);
-                    }
-                    OpCode::Negate => {
-                        let value = self.pop()?;
-                        // let negate_value = -value;
-                        // print_value(negate_value);
-                        // self.push(negate_value)?;
-                        match value {
-                            Value::Number(x) => {
-                                print_value(&Value::Number(-x));
-                                self.push(Value::Number(-x));
-                            }
-                            _ => bail!("Operand must be a number."),
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        if is_local {
+                            // A local of the function that is running right now, still on the stack.
+                            let slot = self.frame().slots + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            // The enclosing function captured it already, share that upvalue.
+                            upvalues.push(self.current_closure().upvalues[index]);
                         }
                     }
-                    OpCode::Add => {
-                        self.binary_op_with_check(|a, b| match (a, b) {
-                            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
-                            _ => Err("Operands must be numbers.".to_string()),
-                        })?;
-                    }
-                    OpCode::Subtract => {
-                        self.binary_op_with_check(|a, b| match (a, b) {
-                            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a - b)),
-                            _ => Err("Operands must be numbers.".to_string()),
-                        })?;
-                    }
-                    OpCode::Multiply => {
-                        self.binary_op_with_check(|a, b| match (a, b) {
-                            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a * b)),
-                            _ => Err("Operands must be numbers.".to_string()),
-                        })?;
-                    }
-                    OpCode::Divide => {
-                        self.binary_op_with_check(|a, b| match (a, b) {
-                            (Value::Number(a), Value::Number(b)) => {
-                                if b == 0.0 {
-                                    Err("Division by zero".to_string())
-                                } else {
-                                    Ok(Value::Number(a / b))
-                                }
-                            },
-                            _ => Err("Operands must be numbers.".to_string()),
-                        })?;
-                    }
-                    OpCode:: Not => {
-                        let value = self.pop()?;
-                        self.push(Value::Bool(value.is_falsy()))?;
-                    }
-                    OpCode:: Greater => {
-                        self.binary_op_with_check(|a, b| match (a, b) {
-                            (Value::Number(a), Value::Number(b)) => Ok(Value::Bool(a > b)),
-                            _ => Err("Operands must be numbers.".to_string()),
-                        })?;
-                    }
-                    OpCode:: Less => {
-                        self.binary_op_with_check(|a, b| match (a, b) {
-                            (Value::Number(a), Value::Number(b)) => Ok(Value::Bool(a < b)),
-                            _ => Err("Operands must be numbers.".to_string()),
-                        })?;
-                    }
-                    OpCode:: Equal => {
-                        let b = self.pop()?;
-                        let a = self.pop()?;
-                        self.push(Value::Bool(a == b))?;
+
+                    let closure = self.alloc(Obj::Closure(ObjClosure { function, upvalues }));
+                    self.push(Value::Obj(closure))?;
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop()?;
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte() as usize;
+                    self.call_value(self.peek(arg_count), arg_count)?;
+                }
+                OpCode::CallNamed => {
+                    let arg_count = self.read_byte() as usize;
+                    let named_count = self.read_byte() as usize;
+                    let names: Vec<ObjRef> = (0..named_count).map(|_| self.read_string()).collect();
+                    let arg_count = self.match_named_arguments(self.peek(arg_count), arg_count, &names)?;
+                    self.call_value(self.peek(arg_count), arg_count)?;
+                }
+                OpCode::Shuffle => {
+                    // The compiler already matched the names, see Compiler::call(). The OP_CALL right after calls.
+                    let arg_count = self.read_byte() as usize;
+                    let parameter_count = self.read_byte() as usize;
+                    let args_start = self.stack.len() - arg_count;
+                    let mut shuffled = Vec::with_capacity(parameter_count);
+                    for _ in 0..parameter_count {
+                        shuffled.push(match self.read_byte() {
+                            NOT_GIVEN => Value::Obj(self.no_argument),
+                            argument => self.stack[args_start + argument as usize],
+                        });
                     }
-                },
-                Err(_) => {
-                    bail!("Unknown opcode: {}", instruction);
+                    self.stack.truncate(args_start);
+                    for value in shuffled {
+                        self.push(value)?;
+                    }
+                }
+                OpCode::Class => {
+                    let name = self.read_string();
+                    let class = self.alloc(Obj::Class(ObjClass::new(name)));
+                    self.push(Value::Obj(class))?;
+                }
+                OpCode::GetProperty => {
+                    let name = self.read_string();
+                    if let Value::Obj(module) = self.peek(0)
+                        && module.as_module().is_some()
+                    {
+                        let value = self.module_export(module, name)?;
+                        self.pop()?; // Module
+                        self.push(value)?;
+                        return Ok(None);
+                    }
+                    let instance_ref = match self.peek(0) {
+                        Value::Obj(object) if object.as_instance().is_some() => object,
+                        _ => return Err(self.runtime_error("Only instances have properties.")),
+                    };
+                    let instance = instance_ref.as_instance().expect("Checked above");
+
+                    // Fields shadow methods with the same name.
+                    let field = instance.fields.borrow().get(Value::Obj(name));
+                    match field {
+                        Some(value) => {
+                            self.pop()?; // Instance
+                            self.push(value)?;
+                        }
+                        None => self.bind_method(instance.class, name)?,
+                    }
+                }
+                OpCode::SetProperty => {
+                    let name = self.read_string();
+                    let instance = match self.peek(1) {
+                        Value::Obj(object) if object.as_instance().is_some() => object,
+                        _ => return Err(self.runtime_error("Only instances have fields.")),
+                    };
+                    let value = self.peek(0);
+                    instance.as_instance().expect("Checked above").fields.borrow_mut().set(Value::Obj(name), value);
+                    self.remeasure(instance);
+
+                    // instance.field = value evaluates to value, so drop the instance but keep the value.
+                    let value = self.pop()?;
+                    self.pop()?;
+                    self.push(value)?;
+                }
+                OpCode::Method => {
+                    let name = self.read_string();
+                    self.define_method(name)?;
+                }
+                OpCode::Invoke => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte() as usize;
+                    self.invoke(name, arg_count)?;
+                }
+                OpCode::Inherit => {
+                    // Stack: [ superclass ][ subclass ]
+                    let superclass = match self.peek(1) {
+                        Value::Obj(object) if object.as_class().is_some() => object,
+                        _ => return Err(self.runtime_error("Superclass must be a class.")),
+                    };
+                    let subclass = match self.peek(0) {
+                        Value::Obj(object) => object,
+                        _ => unreachable!("OP_INHERIT always follows the subclass being loaded"),
+                    };
+                    // Copy-down inheritance: the methods are copied once, before the subclass defines its own,
+                    // so overriding just replaces the copy and method lookups never walk up the hierarchy.
+                    let super_methods = superclass.as_class().expect("Checked above").methods.borrow();
+                    super_methods.add_all(&mut subclass.as_class().expect("Created by OP_CLASS").methods.borrow_mut());
+                    drop(super_methods);
+                    self.remeasure(subclass);
+                    self.pop()?; // Subclass
+                }
+                OpCode::GetSuper => {
+                    let name = self.read_string();
+                    let superclass = self.pop_class()?;
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::SuperInvoke => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte() as usize;
+                    let superclass = self.pop_class()?;
+                    self.invoke_from_class(superclass, name, arg_count)?;
+                }
+                OpCode::BuildList => {
+                    let item_count = self.read_byte() as usize;
+                    let items_start = self.stack.len() - item_count;
+                    // The items stay on the stack until the list owns them, in case allocating collects.
+                    let items = self.stack[items_start..].to_vec();
+                    let list = self.alloc(Obj::List(ObjList::new(items)));
+                    self.stack.truncate(items_start);
+                    self.push(Value::Obj(list))?;
+                }
+                OpCode::BuildMap => {
+                    let entry_count = self.read_byte() as usize;
+                    let entries_start = self.stack.len() - entry_count * 2;
+                    let map = self.alloc(Obj::Map(ObjMap::new()));
+                    // Keys and values alternate on the stack: [ k1 ][ v1 ][ k2 ][ v2 ] ...
+                    for i in (entries_start..self.stack.len()).step_by(2) {
+                        let key = self.check_key(self.stack[i])?;
+                        map.as_map().expect("Just allocated").entries.borrow_mut().set(key, self.stack[i + 1]);
+                    }
+                    self.remeasure(map);
+                    self.stack.truncate(entries_start);
+                    self.push(Value::Obj(map))?;
+                }
+                OpCode::ToString => {
+                    let value = self.peek(0);
+                    if !value.is_string() {
+                        // Peek, not pop: the value must stay reachable while the string is allocated.
+                        let string = self.alloc_string(value.to_string());
+                        self.pop()?;
+                        self.push(Value::Obj(string))?;
+                    }
+                }
+                OpCode::IndexGet => {
+                    // Stack: [ list or map ][ index ]
+                    let item = self.index_get(self.peek(1), self.peek(0))?;
+                    self.pop()?;
+                    self.pop()?;
+                    self.push(item)?;
+                }
+                OpCode::IndexSet => {
+                    // Stack: [ list or map ][ index ][ value ]
+                    let value = self.peek(0);
+                    self.index_set(self.peek(2), self.peek(1), value)?;
+
+                    // Like any assignment, this evaluates to the assigned value.
+                    self.pop()?;
+                    self.pop()?;
+                    self.pop()?;
+                    self.push(value)?;
+                }
+                OpCode::Return => {
+                    let result = self.pop()?;
+                    if let Some(result) = self.return_from_call(result)? {
+                        return Ok(Some(result));
+                    }
+                }
+                OpCode::Throw => {
+                    let exception = self.peek(0);
+                    // Remember where an instance was thrown, unless it already knows.
+                    if let Value::Obj(object) = exception
+                        && let Some(instance) = object.as_instance()
+                    {
+                        let line_name = self.alloc_string("line".to_string());
+                        let mut fields = instance.fields.borrow_mut();
+                        if fields.get(Value::Obj(line_name)).is_none() {
+                            fields.set(Value::Obj(line_name), Value::Int(self.current_line() as i64));
+                        }
+                        drop(fields);
+                        self.remeasure(object);
+                    }
+                    self.pop()?;
+                    self.throw(exception)?;
+                }
+                OpCode::Import => {
+                    let path = self.read_string();
+                    self.import(path)?;
+                }
+                OpCode::ImportName => {
+                    let name = self.read_string();
+                    let module = match self.peek(0) {
+                        Value::Obj(module) => module,
+                        _ => unreachable!("OP_IMPORT_NAME always follows OP_IMPORT"),
+                    };
+                    let value = self.module_export(module, name)?;
+                    self.push(value)?;
+                }
+                OpCode::EndFinally => {
+                    // Stack: [ value ][ completion ], see chunk::COMPLETION_NORMAL
+                    let completion = self.peek(0).as_number().expect("Pushed by the compiler or the VM");
+                    let value = self.peek(1);
+                    if completion == COMPLETION_THROW {
+                        self.throw(value)?;
+                    } else if completion == COMPLETION_RETURN
+                        && let Some(result) = self.return_from_call(value)?
+                    {
+                        return Ok(Some(result));
+                    }
+                    // COMPLETION_NORMAL: carry on, the two hidden locals are popped right after this.
+                }
+                OpCode::Negate => {
+                    let value = self.pop()?;
+                    match number::negate(value) {
+                        Ok(result) => self.push_computed(result)?,
+                        Err(error) => return Err(self.runtime_error(error)),
+                    }
+                }
+                OpCode::Add => {
+                    if self.peek(0).is_string() && self.peek(1).is_string() {
+                        self.concatenate()?;
+                    } else {
+                        self.binary_op_with_check(|a, b| number::arithmetic(Arithmetic::Add, a, b))?;
+                    }
+                }
+                OpCode::Subtract => {
+                    self.binary_op_with_check(|a, b| number::arithmetic(Arithmetic::Subtract, a, b))?;
+                }
+                OpCode::Multiply => {
+                    self.binary_op_with_check(|a, b| number::arithmetic(Arithmetic::Multiply, a, b))?;
+                }
+                OpCode::Divide => {
+                    self.binary_op_with_check(|a, b| number::arithmetic(Arithmetic::Divide, a, b))?;
+                }
+                OpCode::Modulo => {
+                    self.binary_op_with_check(|a, b| number::arithmetic(Arithmetic::Modulo, a, b))?;
+                }
+                OpCode::BitAnd => {
+                    self.binary_op_with_check(|a, b| number::bitwise(a, b, |a, b| Ok(a & b)))?;
+                }
+                OpCode::BitOr => {
+                    self.binary_op_with_check(|a, b| number::bitwise(a, b, |a, b| Ok(a | b)))?;
+                }
+                OpCode::BitXor => {
+                    self.binary_op_with_check(|a, b| number::bitwise(a, b, |a, b| Ok(a ^ b)))?;
+                }
+                OpCode::ShiftLeft => {
+                    self.binary_op_with_check(|a, b| number::bitwise(a, b, number::shift_left))?;
+                }
+                OpCode::ShiftRight => {
+                    self.binary_op_with_check(|a, b| {
+                        number::bitwise(a, b, |a, b| Ok(a >> number::shift_amount(b)?))
+                    })?;
                 }
+                OpCode::BitNot => {
+                    match self.pop()? {
+                        Value::Int(x) => self.push(Value::Int(!x))?,
+                        _ => return Err(self.runtime_error("Operand must be an integer.")),
+                    }
+                }
+                OpCode::Yield => {
+                    let value = self.pop()?;
+                    self.suspend_generator(value)?;
+                }
+                OpCode::GetIter => {
+                    let iterable = self.peek(0);
+                    self.get_iterator(iterable)?;
+                }
+                OpCode::IterNext => {
+                    let iterator = self.peek(0);
+                    self.iterator_next(iterator)?;
+                }
+                OpCode::IterCheck => {
+                    let offset = self.read_short();
+                    if self.peek(0) == self.done_value() {
+                        self.pop()?;
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::MatchLen => {
+                    let length = self.read_byte() as usize;
+                    let value = self.pop()?;
+                    let matches = match value {
+                        Value::Obj(object) => object.as_list().is_some_and(|list| list.items.borrow().len() == length),
+                        _ => false,
+                    };
+                    self.push(Value::Bool(matches))?;
+                }
+                OpCode::MatchRange => {
+                    // Stack: [ value ][ low ][ high ]
+                    let high = self.pop()?;
+                    let low = self.pop()?;
+                    let value = self.pop()?;
+                    // A value that is not a number is simply not in the range.
+                    let at_least = |bound| matches!(number::compare(value, bound), Ok(Some(order)) if order.is_ge());
+                    let at_most = |bound| matches!(number::compare(value, bound), Ok(Some(order)) if order.is_le());
+                    self.push(Value::Bool(at_least(low) && at_most(high)))?;
+                }
+                OpCode::JumpTable => {
+                    let min = self.read_constant().as_int().expect("The table starts at an int");
+                    let length = self.read_byte() as usize;
+                    let value = self.pop()?;
+                    // Entry 0 is the default, entry i + 1 is for the value min + i.
+                    let entry = value
+                        .as_int()
+                        .and_then(|n| n.checked_sub(min))
+                        .and_then(|index| usize::try_from(index).ok())
+                        .filter(|&index| index < length)
+                        .map_or(0, |index| index + 1);
+                    let table = self.frame().ip;
+                    self.frame_mut().ip = table + 2 * entry;
+                    let offset = self.read_short() as usize;
+                    self.frame_mut().ip = table + 2 * (length + 1) - offset;
+                }
+                OpCode::NoMatch => {
+                    let value = self.pop()?;
+                    return Err(self.runtime_error(format!("No match arm for {}.", value)));
+                }
+                OpCode:: Not => {
+                    let value = self.pop()?;
+                    self.push(Value::Bool(value.is_falsy()))?;
+                }
+                OpCode:: Greater => {
+                    self.binary_op_with_check(|a, b| {
+                        number::compare(a, b).map(|order| Value::Bool(order == Some(Ordering::Greater)).into())
+                    })?;
+                }
+                OpCode:: Less => {
+                    self.binary_op_with_check(|a, b| {
+                        number::compare(a, b).map(|order| Value::Bool(order == Some(Ordering::Less)).into())
+                    })?;
+                }
+                OpCode:: Equal => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.push(Value::Bool(a == b))?;
+                }
+            },
+            Err(_) => {
+                return Err(self.runtime_error(format!("Unknown opcode: {}", instruction)));
+            }
+        }
+        Ok(None)
+    }
+
+    // Push the module for `path`, loading it first if this is the first import of it:
+    /*
+        [ module ][ <script> ]   <- first import: its top-level code is called like a function
+        [ module ][ nil ]        <- already loaded, or once the top-level code has returned
+    */
+    fn import(&mut self, path: ObjRef) -> Result<()> {
+        let path = path.as_string().expect("Import paths are strings").chars.clone();
+        let file = self.resolve_import(&path)?;
+        let name = self.alloc_string(file.display().to_string());
+
+        if let Some(Value::Obj(module)) = self.modules.get(Value::Obj(name)) {
+            if !module.as_module().expect("Only modules are cached").is_loaded.get() {
+                return Err(self.import_cycle(name));
+            }
+            self.push(Value::Obj(module))?;
+            return self.push(Value::Nil);
+        }
+
+        let source = match fs::read_to_string(&file) {
+            Ok(source) => source,
+            Err(err) => return Err(self.runtime_error(format!("Could not read module '{}': {}.", *name, err))),
+        };
+        self.push(Value::Obj(name))?;
+        let directory = file.parent().map(Path::to_path_buf).unwrap_or_default();
+        let module = self.alloc(Obj::Module(ObjModule::new(name, directory)));
+        self.pop()?;
+        self.push(Value::Obj(module))?;
+        self.modules.set(Value::Obj(name), Value::Obj(module));
+
+        let function = compiler::compile(source, self, module);
+        self.compiler_roots.clear();
+        let Some(function) = function else {
+            // Forget it, so that importing it again reports the compile errors again instead of a cycle.
+            self.modules.delete(Value::Obj(name));
+            return Err(self.runtime_error(format!("Could not compile module '{}'.", *name)));
+        };
+        self.push(Value::Obj(function))?;
+        let closure = self.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.pop()?;
+        self.push(Value::Obj(closure))?;
+        self.call(closure, 0)
+    }
+
+    // Look next to the importing module first, then in every search path, in order.
+    fn resolve_import(&mut self, path: &str) -> Result<PathBuf> {
+        let module = self.current_module();
+        let importer_directory = &module.as_module().expect("Functions belong to a module").directory;
+        let candidates = iter::once(importer_directory).chain(self.search_paths.iter());
+        if let Some(file) = candidates.map(|directory| directory.join(path)).find(|file| file.is_file()) {
+            return Ok(fs::canonicalize(&file).unwrap_or(file));
+        }
+        Err(self.runtime_error(format!("Could not find module '{}'.", path)))
+    }
+
+    // "Import cycle: main.lox -> a.lox -> main.lox", the modules still loading are the top-level frames.
+    fn import_cycle(&mut self, name: ObjRef) -> anyhow::Error {
+        let mut chain: Vec<String> = self
+            .frames
+            .iter()
+            .map(|frame| frame.closure.as_closure().expect("Frames always run closures").function)
+            .filter_map(|function| {
+                let function = function.as_function().expect("Closures always wrap functions");
+                match function.name {
+                    Some(_) => None,
+                    None => Some(function.module.as_module().expect("Functions belong to a module").name.to_string()),
+                }
+            })
+            .collect();
+        chain.push(name.to_string());
+        self.runtime_error(format!("Import cycle: {}.", chain.join(" -> ")))
+    }
+
+    // What `module.name` or `import { name }` evaluates to.
+    fn module_export(&mut self, module: ObjRef, name: ObjRef) -> Result<Value> {
+        let module = module.as_module().expect("Only called with modules");
+        if module.exports.borrow().get(Value::Obj(name)).is_none() {
+            return Err(self.runtime_error(format!("Module '{}' does not export '{}'.", *module.name, *name)));
+        }
+        let value = module.globals.borrow().get(Value::Obj(name));
+        match value {
+            Some(value) => Ok(value),
+            // Exported, but its declaration has not run yet.
+            None => Err(self.runtime_error(format!("Undefined variable '{}'.", *name))),
+        }
+    }
+
+    fn current_module(&self) -> ObjRef {
+        self.current_closure().function.as_function().expect("Closures always wrap functions").module
+    }
+
+    // The global variables of the module the running code belongs to.
+    fn globals(&self) -> &RefCell<Table> {
+        let function = self.current_closure().function.as_function().expect("Closures always wrap functions");
+        &function.module.as_module().expect("Functions belong to a module").globals
+    }
+
+    fn const_globals(&self) -> &RefCell<Table> {
+        let function = self.current_closure().function.as_function().expect("Closures always wrap functions");
+        &function.module.as_module().expect("Functions belong to a module").const_globals
+    }
+
+    fn check_not_const(&mut self, name: ObjRef, action: &str) -> Result<()> {
+        if self.const_globals().borrow().get(Value::Obj(name)).is_some() {
+            return Err(self.runtime_error(format!("Can't {} constant '{}'.", action, *name)));
+        }
+        Ok(())
+    }
+
+    // Leave the current call with `result`. Some(result) once the top-level script returned.
+    fn return_from_call(&mut self, result: Value) -> Result<Option<InterpretResult>> {
+        // A finally block around the return runs first, its OP_END_FINALLY continues returning.
+        let top = self.frames.len() - 1;
+        if let Some(handler) = self.find_handler(top, true) {
+            self.enter_handler(top, handler, result, COMPLETION_RETURN);
+            return Ok(None);
+        }
+
+        let frame = self.frames.pop().expect("Returning without a call frame");
+        // The parameters and locals of this call are about to disappear.
+        self.close_upvalues(frame.slots);
+        // A generator that runs off its end gives `done` instead of the implicit nil, it can't return a value.
+        let result = match frame.generator {
+            Some(generator) => {
+                generator.as_generator().expect("Frames only point to generators").state.set(GeneratorState::Done);
+                self.done_value()
             }
-            // TODO: Handle different instruction types
+            None => result,
+        };
+        let function = frame.closure.as_closure().expect("Frames always run closures").function;
+        let function = function.as_function().expect("Closures always wrap functions");
+        if function.name.is_none() {
+            // The end of a module's top-level code: it is safe to import it from now on.
+            function.module.as_module().expect("Functions belong to a module").is_loaded.set(true);
+        }
+        if self.frames.is_empty() {
+            // We just returned from the top-level script or the function of a fiber, pop it.
+            self.pop()?;
+            return self.finish_fiber();
         }
+
+        // Throw away the callee and its arguments/locals, then hand the result to the caller.
+        self.stack.truncate(frame.slots);
+        self.push(result)?;
+        Ok(None)
+    }
+
+    // Unwind to the innermost handler, in this call or in one of its callers:
+    /*
+        fun inner() { throw Error("oops"); }     <- no handler here, drop this frame
+        fun outer() {
+            try { inner(); }                     <- ip of this frame is inside the try
+            catch (e) { print e.message; }       <- continue here with e on the stack
+        }
+    */
+    // Err when nobody catches it, with the report and stack trace of where it was thrown.
+    fn throw(&mut self, exception: Value) -> Result<()> {
+        for frame_index in (0..self.frames.len()).rev() {
+            if let Some(handler) = self.find_handler(frame_index, false) {
+                self.enter_handler(frame_index, handler, exception, COMPLETION_THROW);
+                return Ok(());
+            }
+        }
+
+        let message = match exception {
+            Value::Obj(object) if object.as_instance().is_some() => {
+                // Keep the exception reachable while allocating, the stack is reset right after anyway.
+                self.stack.push(exception);
+                let message_name = self.alloc_string("message".to_string());
+                let fields = object.as_instance().expect("Checked above").fields.borrow();
+                fields.get(Value::Obj(message_name)).map(|message| message.to_string())
+            }
+            _ => None,
+        };
+        let message = message.unwrap_or_else(|| format!("Uncaught exception: {}", exception));
+        Err(self.stack_trace(message))
+    }
+
+    fn find_handler(&self, frame_index: usize, finally_only: bool) -> Option<ExceptionHandler> {
+        let frame = &self.frames[frame_index];
+        let closure = frame.closure.as_closure().expect("Frames always run closures");
+        let function = closure.function.as_function().expect("Closures always wrap functions");
+        // ip already points past the instruction that threw (or past the call, in the callers).
+        let ip = frame.ip - 1;
+        function
+            .chunk
+            .handlers()
+            .iter()
+            .find(|handler| (!finally_only || handler.is_finally) && handler.start <= ip && ip < handler.end)
+            .copied()
+    }
+
+    fn enter_handler(&mut self, frame_index: usize, handler: ExceptionHandler, value: Value, completion: f64) {
+        // Drop the calls above the handler, then everything above the locals alive at the `try`.
+        for frame in self.frames.split_off(frame_index + 1) {
+            self.abandon_frame(&frame);
+        }
+        let base = self.frame().slots + handler.stack_depth;
+        self.close_upvalues(base);
+        self.stack.truncate(base);
+
+        // No STACK_MAX check: we just made room, and the exception may be a stack overflow.
+        self.stack.push(value);
+        if handler.is_finally {
+            self.stack.push(Value::Number(completion));
+        }
+        self.frame_mut().ip = handler.target;
+    }
+
+    // An exception unwound past this call, it will never return.
+    fn abandon_frame(&mut self, frame: &CallFrame) {
+        // A generator the exception escaped from can't be resumed.
+        if let Some(generator) = frame.generator {
+            generator.as_generator().expect("Frames only point to generators").state.set(GeneratorState::Done);
+        }
+
+        let function = frame.closure.as_closure().expect("Frames always run closures").function;
+        let function = function.as_function().expect("Closures always wrap functions");
+        let module = function.module;
+        if function.name.is_none() && module != self.main_module {
+            let module = module.as_module().expect("Functions belong to a module");
+            if !module.is_loaded.get() {
+                // Its top-level code failed. Forget it like a module that did not compile,
+                // so that importing it again runs it again instead of reporting a cycle.
+                self.modules.delete(Value::Obj(module.name));
+            }
+        }
+    }
+
+    fn done_value(&self) -> Value {
+        Value::Obj(self.done.expect("The prelude does not iterate"))
+    }
+
+    // An instance of the prelude's Error class, for an error raised by the VM itself.
+    fn make_error(&mut self, message: impl Display) -> Value {
+        let line = self.current_line();
+        let error_class = self.error_class.expect("Only the prelude runs before Error exists");
+
+        // Everything stays on the stack until it is reachable from the instance.
+        let stack_len = self.stack.len();
+        let message = self.alloc_string(message.to_string());
+        self.stack.push(Value::Obj(message));
+        let error = self.alloc(Obj::Instance(ObjInstance::new(error_class)));
+        self.stack.push(Value::Obj(error));
+        let message_name = self.alloc_string("message".to_string());
+        self.stack.push(Value::Obj(message_name));
+        let line_name = self.alloc_string("line".to_string());
+
+        let mut fields = error.as_instance().expect("Just allocated").fields.borrow_mut();
+        fields.set(Value::Obj(message_name), Value::Obj(message));
+        fields.set(Value::Obj(line_name), Value::Int(line as i64));
+        drop(fields);
+        self.remeasure(error);
+        self.stack.truncate(stack_len);
+        Value::Obj(error)
+    }
+
+    fn current_line(&self) -> usize {
+        self.chunk().lines()[self.frame().ip.saturating_sub(1)]
+    }
+
+    // Every allocation goes through here, so that this is where garbage gets collected.
+    // Anything the new object refers to must already be reachable from a root.
+    pub(crate) fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc(obj)
+    }
+
+    // Call after an object grew or shrank, see Heap::remeasure().
+    pub(crate) fn remeasure(&mut self, object: ObjRef) {
+        self.heap.remeasure(object);
+    }
+
+    pub(crate) fn alloc_string(&mut self, chars: String) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc_string(chars)
+    }
+
+    // The compiler calls this for every constant it creates, because the functions
+    // it is compiling are not on the heap yet and so cannot be traced.
+    pub(crate) fn add_compiler_root(&mut self, value: Value) {
+        self.compiler_roots.push(value);
+    }
+
+    fn collect_garbage(&mut self) {
+        self.mark_roots();
+        self.heap.collect_garbage();
+    }
+
+    // Roots are the objects the VM can reach directly, without going through another object.
+    fn mark_roots(&mut self) {
+        for &value in &self.stack {
+            self.heap.mark_value(value);
+        }
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+            // A running generator is not on the stack, only its frame knows about it.
+            if let Some(generator) = frame.generator {
+                self.heap.mark_object(generator);
+            }
+        }
+        for &upvalue in &self.open_upvalues {
+            self.heap.mark_object(upvalue);
+        }
+        self.heap.mark_object(self.main_fiber);
+        self.heap.mark_object(self.current_fiber);
+        for &fiber in &self.ready {
+            self.heap.mark_object(fiber);
+        }
+        self.heap.mark_table(&self.builtins);
+        self.heap.mark_object(self.main_module);
+        self.heap.mark_table(&self.modules);
+        self.heap.mark_object(self.init_string);
+        self.heap.mark_object(self.iterator_string);
+        self.heap.mark_object(self.next_string);
+        self.heap.mark_object(self.no_argument);
+        if let Some(error_class) = self.error_class {
+            self.heap.mark_object(error_class);
+        }
+        if let Some(done) = self.done {
+            self.heap.mark_object(done);
+        }
+        for &value in &self.compiler_roots {
+            self.heap.mark_value(value);
+        }
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("No function is running")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("No function is running")
+    }
+
+    fn current_closure(&self) -> &ObjClosure {
+        self.frame().closure.as_closure().expect("Frames always run closures")
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.current_closure().function.as_function().expect("Closures always wrap functions").chunk
     }
 
     fn read_byte(&mut self) -> u8 {
-        // TODO: Read byte at IP and advance IP
-        let chunk = self.chunk.as_ref().expect("No chunk loaded");
-        // .as_ref() is to change from Option(Chunk) to Option(&Chunk) 
-        // so that it becomes &Chunk after unwrapping to make it borrowing instead of ownership taking
-        let byte = chunk.code()[self.ip];
-        self.ip += 1;
+        // Read byte at IP and advance IP
+        let byte = self.chunk().code()[self.frame().ip];
+        self.frame_mut().ip += 1;
         byte
     }
 
+    fn read_short(&mut self) -> u16 {
+        // Jump offsets are stored as two bytes, high byte first.
+        let high = self.read_byte();
+        let low = self.read_byte();
+        u16::from_be_bytes([high, low])
+    }
+
     fn read_constant(&mut self) -> Value {
-        // TODO: Read a constant index and return the constant
         let constant_index = self.read_byte() as usize;
         // Don't forget that constant byte in `Chunk` is only an index refering to `constants`
-        let chunk = self.chunk.as_ref().expect("No Chunk Loaded");
-        chunk.constants().get(constant_index).expect("Invalid constant index").clone()
+        *self.chunk().constants().get(constant_index).expect("Invalid constant index")
+    }
+
+    fn read_string(&mut self) -> ObjRef {
+        match self.read_constant() {
+            Value::Obj(string) => string,
+            _ => unreachable!("Variable names are always string constants"),
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<()> {
+        if let Value::Obj(object) = callee {
+            match &*object {
+                Obj::Closure(_) => return self.call(object, arg_count),
+                Obj::Native(native) => return self.call_native(native, arg_count),
+                Obj::BoundMethod(bound) => {
+                    // Put the receiver where the method expects `this`: slot 0 of the new frame.
+                    let slot = self.stack.len() - arg_count - 1;
+                    self.stack[slot] = bound.receiver;
+                    return self.call(bound.method, arg_count);
+                }
+                Obj::Class(class) => {
+                    // Calling a class creates an instance, which replaces the class on the stack.
+                    let instance = self.alloc(Obj::Instance(ObjInstance::new(object)));
+                    let slot = self.stack.len() - arg_count - 1;
+                    self.stack[slot] = Value::Obj(instance);
+
+                    let initializer = class.methods.borrow().get(Value::Obj(self.init_string));
+                    return match initializer {
+                        Some(Value::Obj(initializer)) => self.call(initializer, arg_count),
+                        _ if arg_count != 0 => {
+                            Err(self.runtime_error(format!("Expected 0 arguments but got {}.", arg_count)))
+                        }
+                        _ => Ok(()),
+                    };
+                }
+                _ => {}
+            }
+        }
+        Err(self.runtime_error("Can only call functions and classes."))
+    }
+
+    // Natives don't get a call frame, they run to completion right here.
+    fn call_native(&mut self, native: &ObjNative, arg_count: usize) -> Result<()> {
+        if arg_count != native.arity {
+            return Err(self.runtime_error(format!(
+                "Expected {} arguments but got {}.",
+                native.arity, arg_count
+            )));
+        }
+
+        // The arguments stay on the stack during the call so the GC still sees them.
+        let args_start = self.stack.len() - arg_count;
+        let args = self.stack[args_start..].to_vec();
+        let result = match (native.function)(self, &args) {
+            Ok(result) => result,
+            Err(err) => return Err(self.runtime_error(format!("{}: {}", *native.name, err))),
+        };
+
+        // Pop the arguments and the native itself, then push the result.
+        self.stack.truncate(args_start - 1);
+        self.push(result)?;
+        if self.pause_requested {
+            self.pause_requested = false;
+            self.reschedule();
+        }
+        Ok(())
+    }
+
+    fn invoke(&mut self, name: ObjRef, arg_count: usize) -> Result<()> {
+        let receiver = self.peek(arg_count);
+        // util.f(x): call the exported function, there is no receiver to bind.
+        if let Value::Obj(module) = receiver
+            && module.as_module().is_some()
+        {
+            let function = self.module_export(module, name)?;
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = function;
+            return self.call_value(function, arg_count);
+        }
+
+        if let Value::Obj(channel) = receiver
+            && channel.as_channel().is_some()
+        {
+            return self.invoke_channel(channel, name, arg_count);
+        }
+
+        // gen.next(): a generator's only method.
+        if let Value::Obj(generator) = receiver
+            && generator.as_generator().is_some()
+        {
+            if name != self.next_string {
+                return Err(self.runtime_error(format!("Generators have no '{}' method, only 'next'.", *name)));
+            }
+            if arg_count != 0 {
+                return Err(self.runtime_error(format!("Expected 0 arguments but got {}.", arg_count)));
+            }
+            return self.resume_generator(generator);
+        }
+
+        let instance = match receiver {
+            Value::Obj(object) if object.as_instance().is_some() => object,
+            _ => return Err(self.runtime_error("Only instances have methods.")),
+        };
+        let instance = instance.as_instance().expect("Checked above");
+
+        // It might be a field holding a function, not a method: call it like any other value.
+        let field = instance.fields.borrow().get(Value::Obj(name));
+        if let Some(value) = field {
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        self.invoke_from_class(instance.class, name, arg_count)
+    }
+
+    fn invoke_from_class(&mut self, class: ObjRef, name: ObjRef, arg_count: usize) -> Result<()> {
+        let method = class.as_class().expect("Only classes have methods").methods.borrow().get(Value::Obj(name));
+        match method {
+            // The receiver is already in slot 0, no need for a bound method.
+            Some(Value::Obj(method)) => self.call(method, arg_count),
+            _ => Err(self.runtime_error(format!("Undefined property '{}'.", *name))),
+        }
+    }
+
+    // spawn(fn): a fiber that will call fn once the scheduler gets to it. The caller keeps running.
+    pub(crate) fn spawn(&mut self, function: Value) -> Result<Value> {
+        let closure = match function {
+            Value::Obj(object) if object.as_closure().is_some() => object,
+            _ => bail!("Expected a function but got a {}.", function.type_name()),
+        };
+        let body = closure.as_closure().expect("Checked above").function;
+        let body = body.as_function().expect("Closures always wrap functions");
+        if !body.parameters.is_empty() {
+            bail!("Expected a function without parameters but it takes {}.", body.parameters.len());
+        }
+        if body.is_generator {
+            bail!("Can't run a generator function in a fiber.");
+        }
+
+        // The fiber starts out like a call to the function with zero arguments.
+        let frame = CallFrame { closure, ip: 0, slots: 0, generator: None };
+        let fiber = self.alloc(Obj::Fiber(ObjFiber::new(vec![function], vec![frame], FiberState::Ready)));
+        self.ready.push_back(fiber);
+        Ok(Value::Obj(fiber))
+    }
+
+    // pause(): let the other fibers run before continuing.
+    pub(crate) fn request_pause(&mut self) {
+        self.pause_requested = true;
+    }
+
+    // The running fiber goes to the back of the queue. Round-robin: every ready fiber gets a turn first.
+    fn reschedule(&mut self) {
+        if let Some(next) = self.ready.pop_front() {
+            let current = self.current_fiber;
+            current.as_fiber().expect("Only fibers run").state.set(FiberState::Ready);
+            self.ready.push_back(current);
+            self.switch_to(next);
+        }
+    }
+
+    // The running fiber can't go on until some other fiber uses the channel.
+    // Once woken up it runs the instruction that got it here again, so the operation is retried from scratch.
+    // Nothing has been popped yet, so the receiver and the arguments are still where that instruction expects them.
+    fn block_on(&mut self, channel: ObjRef) -> Result<()> {
+        let Some(next) = self.ready.pop_front() else {
+            return Err(self.runtime_error("Deadlock: every fiber is waiting on a channel."));
+        };
+        self.frame_mut().ip = self.instruction_start;
+        let current = self.current_fiber;
+        current.as_fiber().expect("Only fibers run").state.set(FiberState::Blocked);
+        self.blocked_fibers += 1;
+        channel.as_channel().expect("Only channels block").waiting.borrow_mut().push(current);
+        self.remeasure(channel);
+        self.switch_to(next);
+        Ok(())
+    }
+
+    // Something changed on the channel, so every fiber blocked on it gets to try again.
+    fn wake(&mut self, channel: &ObjChannel) {
+        for fiber in channel.waiting.borrow_mut().drain(..) {
+            let state = &fiber.as_fiber().expect("Only fibers wait").state;
+            // It may have been woken up some other way already, e.g. the deadlock error.
+            if state.get() == FiberState::Blocked {
+                state.set(FiberState::Ready);
+                self.blocked_fibers -= 1;
+                self.ready.push_back(fiber);
+            }
+        }
+    }
+
+    // The running fiber returned from its function. Some(result) when there is nothing left to run.
+    fn finish_fiber(&mut self) -> Result<Option<InterpretResult>> {
+        self.current_fiber.as_fiber().expect("Only fibers run").state.set(FiberState::Done);
+        if let Some(next) = self.ready.pop_front() {
+            self.switch_to(next);
+            return Ok(None);
+        }
+
+        // Nothing is ready: we are done, unless the script itself is still waiting on a channel,
+        // which nobody can ever send to or receive from anymore.
+        let main = self.main_fiber;
+        let main_state = main.as_fiber().expect("The main fiber is a fiber").state.get();
+        if self.current_fiber != main {
+            self.switch_to(main);
+        }
+        if main_state == FiberState::Blocked {
+            // The error is raised in the script, which is running again.
+            self.blocked_fibers -= 1;
+            return Err(self.runtime_error("Deadlock: every fiber is waiting on a channel."));
+        }
+        // The script is done, but fibers that are still waiting would be dropped without ever finishing.
+        match self.blocked_fibers {
+            0 => {}
+            1 => return Err(self.runtime_error("Deadlock: a fiber is still waiting on a channel when the script ends.")),
+            count => {
+                return Err(self.runtime_error(format!(
+                    "Deadlock: {} fibers are still waiting on a channel when the script ends.",
+                    count
+                )));
+            }
+        }
+        main.as_fiber().expect("The main fiber is a fiber").state.set(FiberState::Running);
+        Ok(Some(InterpretResult::Ok))
+    }
+
+    // Park the running fiber and continue with another one:
+    /*
+        VM.stack, VM.frames, VM.open_upvalues  --save-->  current fiber
+        next fiber                             --load-->  VM.stack, VM.frames, VM.open_upvalues
+    */
+    // The parked fiber's open upvalues become Suspended, so closures that escaped to other fibers
+    // still find their variables.
+    fn switch_to(&mut self, fiber: ObjRef) {
+        let current = self.current_fiber;
+        for &upvalue in &self.open_upvalues {
+            if let UpvalueState::Open(index) = self.upvalue_state(upvalue) {
+                Self::set_upvalue_state(upvalue, UpvalueState::Suspended(current, index));
+            }
+        }
+        let parked = current.as_fiber().expect("Only fibers run");
+        *parked.stack.borrow_mut() = mem::take(&mut self.stack);
+        *parked.frames.borrow_mut() = mem::take(&mut self.frames);
+        *parked.upvalues.borrow_mut() = mem::take(&mut self.open_upvalues);
+
+        let next = fiber.as_fiber().expect("Only fibers can be switched to");
+        self.stack = mem::take(&mut *next.stack.borrow_mut());
+        self.frames = mem::take(&mut *next.frames.borrow_mut());
+        self.open_upvalues = mem::take(&mut *next.upvalues.borrow_mut());
+        for &upvalue in &self.open_upvalues {
+            if let UpvalueState::Suspended(_, index) = self.upvalue_state(upvalue) {
+                Self::set_upvalue_state(upvalue, UpvalueState::Open(index));
+            }
+        }
+        next.state.set(FiberState::Running);
+        self.current_fiber = fiber;
+        // The VM's own stack isn't on the heap, only a parked fiber's is.
+        self.remeasure(current);
+        self.remeasure(fiber);
+    }
+
+    // ch.send(value), ch.receive() and ch.close().
+    fn invoke_channel(&mut self, object: ObjRef, name: ObjRef, arg_count: usize) -> Result<()> {
+        let channel = object.as_channel().expect("Checked by invoke");
+        let method = name.as_string().expect("Method names are strings").chars.as_str();
+        let arity = match method {
+            "send" => 1,
+            "receive" | "close" => 0,
+            _ => return Err(self.runtime_error(format!("Channels have no '{}' method.", method))),
+        };
+        if arg_count != arity {
+            return Err(self.runtime_error(format!("Expected {} arguments but got {}.", arity, arg_count)));
+        }
+
+        let result = match method {
+            "send" => {
+                if channel.closed.get() {
+                    return Err(self.runtime_error("Can't send on a closed channel."));
+                }
+                if channel.buffer.borrow().len() >= channel.capacity {
+                    return self.block_on(object);
+                }
+                channel.buffer.borrow_mut().push_back(self.peek(0));
+                self.remeasure(object);
+                Value::Nil
+            }
+            "receive" => {
+                let item = channel.buffer.borrow_mut().pop_front();
+                match item {
+                    Some(item) => item,
+                    None if channel.closed.get() => Value::Nil,
+                    None => return self.block_on(object),
+                }
+            }
+            _ => {
+                channel.closed.set(true);
+                Value::Nil
+            }
+        };
+        self.wake(channel);
+
+        // Replace the receiver and the arguments with the result, like a call would.
+        self.stack.truncate(self.stack.len() - arg_count - 1);
+        self.push(result)
+    }
+
+    // Replace the iterable on top of the stack with an iterator over it.
+    fn get_iterator(&mut self, iterable: Value) -> Result<()> {
+        let Value::Obj(object) = iterable else {
+            return Err(self.runtime_error(format!("Can't iterate over a {}.", iterable.type_name())));
+        };
+        let iterable = match &*object {
+            Obj::List(_) => Iterable::List(object),
+            Obj::String(_) => Iterable::String(object),
+            Obj::Map(_) => Iterable::MapKeys(object),
+            Obj::Iterator(_) | Obj::Generator(_) => return Ok(()),
+            Obj::Instance(instance) => {
+                let class = instance.class.as_class().expect("Instances always have a class");
+                let methods = class.methods.borrow();
+                // An object with only a next() method is its own iterator.
+                if methods.get(Value::Obj(self.iterator_string)).is_some() {
+                    drop(methods);
+                    // The iterator() call returns into the slot the object is in.
+                    return self.invoke(self.iterator_string, 0);
+                }
+                if methods.get(Value::Obj(self.next_string)).is_some() {
+                    return Ok(());
+                }
+                return Err(self.runtime_error(format!(
+                    "Can't iterate over a {} instance without an iterator() or next() method.",
+                    *class.name
+                )));
+            }
+            _ => return Err(self.runtime_error(format!("Can't iterate over a {}.", iterable.type_name()))),
+        };
+        // The iterable is still on the stack while the iterator is allocated.
+        let iterator = self.alloc(Obj::Iterator(ObjIterator { iterable, position: Cell::new(0) }));
+        let top = self.stack.len() - 1;
+        self.stack[top] = Value::Obj(iterator);
+        Ok(())
+    }
+
+    // Push the next item. Built-in iterators know right away whether they are done, and skip
+    // the OP_ITER_CHECK after this instruction when they are not. User iterators have their next()
+    // called instead, and OP_ITER_CHECK looks at whether it returned `done`.
+    fn iterator_next(&mut self, iterator: Value) -> Result<()> {
+        if let Value::Obj(object) = iterator
+            && let Some(iterator) = object.as_iterator()
+        {
+            match self.advance_iterator(iterator)? {
+                Some(item) => {
+                    self.push(item)?;
+                    // OP_ITER_CHECK and its 16-bit offset.
+                    self.frame_mut().ip += 3;
+                }
+                None => self.push(self.done_value())?,
+            }
+            return Ok(());
+        }
+        // A copy of the iterator becomes the receiver, next() returns into its slot.
+        // Generators are resumed the same way, invoke() knows about them.
+        self.push(iterator)?;
+        self.invoke(self.next_string, 0)
+    }
+
+    fn advance_iterator(&mut self, iterator: &ObjIterator) -> Result<Option<Value>> {
+        let position = iterator.position.get();
+        let (item, next) = match iterator.iterable {
+            Iterable::List(list) => {
+                let items = list.as_list().expect("Checked by get_iterator").items.borrow();
+                match items.get(position as usize) {
+                    Some(&item) => (item, position + 1),
+                    None => return Ok(None),
+                }
+            }
+            Iterable::String(string) => {
+                let chars = &string.as_string().expect("Checked by get_iterator").chars;
+                let Some(c) = chars[position as usize..].chars().next() else {
+                    return Ok(None);
+                };
+                // The iterator is on the stack and keeps the string alive while the character is allocated.
+                let item = Value::Obj(self.alloc_string(c.to_string()));
+                (item, position + c.len_utf8() as i64)
+            }
+            Iterable::MapKeys(map) => {
+                let entries = map.as_map().expect("Checked by get_iterator").entries.borrow();
+                match entries.next_entry(position as usize) {
+                    Some((slot, key, _)) => (key, slot as i64 + 1),
+                    None => return Ok(None),
+                }
+            }
+            Iterable::Range { end } => {
+                if position >= end {
+                    return Ok(None);
+                }
+                (Value::Int(position), position + 1)
+            }
+        };
+        iterator.position.set(next);
+        Ok(Some(item))
+    }
+
+    // list[index] or map[key]
+    fn index_get(&mut self, container: Value, index: Value) -> Result<Value> {
+        match container {
+            Value::Obj(object) => match &*object {
+                Obj::List(list) => {
+                    let items = list.items.borrow();
+                    let index = self.list_index(index, items.len())?;
+                    Ok(items[index])
+                }
+                Obj::Map(map) => {
+                    let key = self.check_key(index)?;
+                    let value = map.entries.borrow().get(key);
+                    match value {
+                        Some(value) => Ok(value),
+                        None => Err(self.runtime_error(format!("Undefined key '{}'.", key))),
+                    }
+                }
+                _ => Err(self.runtime_error("Only lists and maps can be indexed.")),
+            },
+            _ => Err(self.runtime_error("Only lists and maps can be indexed.")),
+        }
+    }
+
+    // list[index] = value replaces an item, map[key] = value adds or replaces an entry.
+    fn index_set(&mut self, container: Value, index: Value, value: Value) -> Result<()> {
+        match container {
+            Value::Obj(object) => match &*object {
+                Obj::List(list) => {
+                    let len = list.items.borrow().len();
+                    let index = self.list_index(index, len)?;
+                    list.items.borrow_mut()[index] = value;
+                    Ok(())
+                }
+                Obj::Map(map) => {
+                    let key = self.check_key(index)?;
+                    map.entries.borrow_mut().set(key, value);
+                    self.remeasure(object);
+                    Ok(())
+                }
+                _ => Err(self.runtime_error("Only lists and maps can be indexed.")),
+            },
+            _ => Err(self.runtime_error("Only lists and maps can be indexed.")),
+        }
+    }
+
+    fn check_key(&mut self, key: Value) -> Result<Value> {
+        if key.is_hashable() {
+            Ok(key)
+        } else {
+            Err(self.runtime_error(format!("A {} can't be used as a map key.", key.type_name())))
+        }
+    }
+
+    // Turn a Lox number into a position inside a list of `len` items. A float like 2.0 is fine too.
+    fn list_index(&mut self, index: Value, len: usize) -> Result<usize> {
+        let Some(index) = index.as_int() else {
+            return Err(self.runtime_error("List index must be an integer."));
+        };
+        if index < 0 {
+            return Err(self.runtime_error(format!("List index {} can't be negative.", index)));
+        }
+        if index as u64 >= len as u64 {
+            return Err(self.runtime_error(format!(
+                "List index {} out of bounds for a list of length {}.",
+                index, len
+            )));
+        }
+        Ok(index as usize)
+    }
+
+    // The superclass pushed by `super` is always a class, OP_INHERIT checked it.
+    fn pop_class(&mut self) -> Result<ObjRef> {
+        match self.pop()? {
+            Value::Obj(object) if object.as_class().is_some() => Ok(object),
+            _ => unreachable!("`super` always refers to a class"),
+        }
+    }
+
+    // Replace the instance on top of the stack with its method `name`, bound to it.
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<()> {
+        let method = class.as_class().expect("Only classes have methods").methods.borrow().get(Value::Obj(name));
+        let method = match method {
+            Some(Value::Obj(method)) => method,
+            _ => return Err(self.runtime_error(format!("Undefined property '{}'.", *name))),
+        };
+
+        let bound = self.alloc(Obj::BoundMethod(ObjBoundMethod {
+            receiver: self.peek(0),
+            method,
+        }));
+        self.pop()?;
+        self.push(Value::Obj(bound))
+    }
+
+    fn define_method(&mut self, name: ObjRef) -> Result<()> {
+        // Stack: [ class ][ method closure ]
+        let method = self.peek(0);
+        if let Value::Obj(class) = self.peek(1) {
+            class.as_class().expect("OP_METHOD is only emitted inside a class").methods.borrow_mut().set(Value::Obj(name), method);
+            self.remeasure(class);
+        }
+        self.pop()?;
+        Ok(())
+    }
+
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Result<()> {
+        let function = closure.as_closure().expect("Only closures can be called").function;
+        let function = function.as_function().expect("Closures always wrap functions");
+        let required = function.arity - function.optional;
+        if arg_count < required {
+            let missing = function.parameters[arg_count];
+            return Err(self.runtime_error(format!("Missing argument for parameter '{}'.", *missing)));
+        }
+        if arg_count > function.arity && !function.has_rest {
+            let expected = if function.optional > 0 { "at most " } else { "" };
+            return Err(self.runtime_error(format!(
+                "Expected {}{} arguments but got {}.",
+                expected, function.arity, arg_count
+            )));
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        // -1 so that slot 0 is the callee itself
+        let slots = self.stack.len() - arg_count - 1;
+        // The parameters the call left out get their default values once the function runs, see OP_JUMP_IF_GIVEN.
+        for _ in arg_count..function.arity {
+            self.push(Value::Obj(self.no_argument))?;
+        }
+        if function.has_rest {
+            // Every argument after the other parameters goes into the rest parameter's list.
+            let rest_start = slots + 1 + function.arity;
+            let items = self.stack[rest_start..].to_vec();
+            let rest = self.alloc(Obj::List(ObjList::new(items)));
+            self.stack.truncate(rest_start);
+            self.push(Value::Obj(rest))?;
+        }
+
+        if function.is_generator {
+            return self.create_generator(closure, slots);
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots,
+            generator: None,
+        });
+        Ok(())
+    }
+
+    // Put named arguments where their parameters are, so the call can go on like one with positional arguments.
+    // Left out parameters get the placeholder for a missing argument right here, they might come before a named one:
+    /*
+        fun f(a, b = 2, c = 3) {}
+        f(1, c: 5)      [ f ][ 1 ][ 5 ]  ->  [ f ][ 1 ][ no argument ][ 5 ]
+    */
+    // Returns the new argument count.
+    fn match_named_arguments(&mut self, callee: Value, arg_count: usize, names: &[ObjRef]) -> Result<usize> {
+        let closure = match callee {
+            Value::Obj(object) => match &*object {
+                Obj::Closure(_) => Some(object),
+                Obj::BoundMethod(bound) => Some(bound.method),
+                Obj::Class(class) => match class.methods.borrow().get(Value::Obj(self.init_string)) {
+                    Some(Value::Obj(initializer)) => Some(initializer),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        };
+        let Some(closure) = closure else {
+            let message = match callee {
+                Value::Obj(object) if matches!(*object, Obj::Native(_)) => "Native functions don't take named arguments.".to_string(),
+                Value::Obj(object) if object.as_class().is_some() => format!("Expected 0 arguments but got {}.", arg_count),
+                _ => "Can only call functions and classes.".to_string(),
+            };
+            return Err(self.runtime_error(message));
+        };
+        let closure = closure.as_closure().expect("Methods and initializers are closures");
+        let function = closure.function.as_function().expect("Closures always wrap functions");
+        let required = function.arity - function.optional;
+
+        let args_start = self.stack.len() - arg_count;
+        let positional = arg_count - names.len();
+        let mut arguments: Vec<Option<Value>> = self.stack[args_start..args_start + positional].iter().copied().map(Some).collect();
+        arguments.resize(positional.max(function.arity), None);
+        for (&name, &value) in names.iter().zip(&self.stack[args_start + positional..]) {
+            let Some(index) = function.parameters[..function.arity].iter().position(|&parameter| parameter == name) else {
+                return Err(self.runtime_error(format!("No parameter named '{}'.", *name)));
+            };
+            if arguments[index].is_some() {
+                return Err(self.runtime_error(format!("Argument for parameter '{}' given twice.", *name)));
+            }
+            arguments[index] = Some(value);
+        }
+
+        let mut matched = Vec::with_capacity(arguments.len());
+        for (index, argument) in arguments.into_iter().enumerate() {
+            match argument {
+                Some(value) => matched.push(value),
+                None if index >= required => matched.push(Value::Obj(self.no_argument)),
+                None => {
+                    let missing = function.parameters[index];
+                    return Err(self.runtime_error(format!("Missing argument for parameter '{}'.", *missing)));
+                }
+            }
+        }
+        self.stack.truncate(args_start);
+        let arg_count = matched.len();
+        for value in matched {
+            self.push(value)?;
+        }
+        Ok(arg_count)
+    }
+
+    // Calling a generator function runs nothing yet: the callee and its arguments become
+    // the generator's saved stack, and the generator replaces them.
+    fn create_generator(&mut self, closure: ObjRef, slots: usize) -> Result<()> {
+        // The arguments stay on the stack while the generator is allocated.
+        let generator = self.alloc(Obj::Generator(ObjGenerator {
+            closure,
+            ip: Cell::new(0),
+            stack: RefCell::new(self.stack[slots..].to_vec()),
+            upvalues: RefCell::new(Vec::new()),
+            state: Cell::new(GeneratorState::Suspended),
+        }));
+        self.stack.truncate(slots);
+        self.push(Value::Obj(generator))
+    }
+
+    // Run the generator on top of the stack until it yields or returns. Whatever it yields
+    // replaces it on the stack, like the result of a call would.
+    fn resume_generator(&mut self, object: ObjRef) -> Result<()> {
+        let generator = object.as_generator().expect("Checked by the caller");
+        match generator.state.get() {
+            GeneratorState::Running => return Err(self.runtime_error("Generator is already running.")),
+            GeneratorState::Done => {
+                let top = self.stack.len() - 1;
+                self.stack[top] = self.done_value();
+                return Ok(());
+            }
+            GeneratorState::Suspended => {}
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        // Re-attach the saved segment where the generator was.
+        let slots = self.stack.len() - 1;
+        self.stack.truncate(slots);
+        self.stack.extend(generator.stack.borrow_mut().drain(..));
+        // Its upvalues point into the VM stack again. They all are above every other open upvalue.
+        for upvalue in generator.upvalues.borrow_mut().drain(..) {
+            if let UpvalueState::Suspended(_, index) = self.upvalue_state(upvalue) {
+                Self::set_upvalue_state(upvalue, UpvalueState::Open(slots + index));
+            }
+            self.open_upvalues.push(upvalue);
+        }
+        generator.state.set(GeneratorState::Running);
+        self.frames.push(CallFrame {
+            closure: generator.closure,
+            ip: generator.ip.get(),
+            slots,
+            generator: Some(object),
+        });
+        Ok(())
+    }
+
+    // OP_YIELD: detach the running generator's frame and stack segment, and hand the value to its caller.
+    fn suspend_generator(&mut self, value: Value) -> Result<()> {
+        let frame = self.frames.pop().expect("Yielding without a call frame");
+        let object = frame.generator.expect("The compiler only allows yield in generators");
+        let generator = object.as_generator().expect("Frames only point to generators");
+
+        // Upvalues into the segment can't point into the VM stack anymore, but closures that
+        // captured them must still share the variables with the generator, so they follow the segment.
+        let mut upvalues = Vec::new();
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let UpvalueState::Open(index) = self.upvalue_state(upvalue) else {
+                unreachable!("Only open upvalues are in the list");
+            };
+            if index < frame.slots {
+                break;
+            }
+            Self::set_upvalue_state(upvalue, UpvalueState::Suspended(object, index - frame.slots));
+            upvalues.push(upvalue);
+            self.open_upvalues.pop();
+        }
+        upvalues.reverse();
+        *generator.upvalues.borrow_mut() = upvalues;
+
+        *generator.stack.borrow_mut() = self.stack.split_off(frame.slots);
+        generator.ip.set(frame.ip);
+        generator.state.set(GeneratorState::Suspended);
+        self.remeasure(object);
+        self.push(value)
+    }
+
+    fn upvalue_state(&self, upvalue: ObjRef) -> UpvalueState {
+        upvalue.as_upvalue().expect("Closures only capture upvalues").state.get()
+    }
+
+    fn set_upvalue_state(upvalue: ObjRef, state: UpvalueState) {
+        upvalue.as_upvalue().expect("Closures only capture upvalues").state.set(state);
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        // If some closure already captured this slot, share its upvalue so both see the same variable.
+        let position = self.open_upvalues.iter().position(|&upvalue| {
+            matches!(self.upvalue_state(upvalue), UpvalueState::Open(index) if index >= slot)
+        });
+        if let Some(position) = position {
+            let upvalue = self.open_upvalues[position];
+            if matches!(self.upvalue_state(upvalue), UpvalueState::Open(index) if index == slot) {
+                return upvalue;
+            }
+        }
+
+        let upvalue = self.alloc(Obj::Upvalue(ObjUpvalue::new(slot)));
+        // Keep the list sorted by slot so close_upvalues() can stop early.
+        self.open_upvalues.insert(position.unwrap_or(self.open_upvalues.len()), upvalue);
+        upvalue
+    }
+
+    // Close every open upvalue pointing at stack slot `last` or above:
+    // copy the variable out of the stack into the upvalue itself.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let UpvalueState::Open(index) = self.upvalue_state(upvalue) else {
+                unreachable!("Only open upvalues are in the list");
+            };
+            if index < last {
+                break;
+            }
+            Self::set_upvalue_state(upvalue, UpvalueState::Closed(self.stack[index]));
+            self.open_upvalues.pop();
+        }
     }
 
     fn push(&mut self, value: Value) -> Result<()> {
         if self.stack.len() >= STACK_MAX {
-            bail!("Stack overflow");
+            return Err(self.runtime_error("Stack overflow."));
         }
         self.stack.push(value);
         Ok(())
     }
 
     fn pop(&mut self) -> Result<Value> {
-        self.stack.pop().ok_or_else(|| anyhow::anyhow!("Stack underflow"))
+        self.stack.pop().ok_or_else(|| anyhow!("Stack underflow"))
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        // distance = 0 is the top of the stack
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    fn concatenate(&mut self) -> Result<()> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        // Both operands were checked to be strings before calling this.
+        let mut chars = a.as_string().unwrap().to_string();
+        chars.push_str(b.as_string().unwrap());
+        let result = self.alloc_string(chars);
+        self.push(Value::Obj(result))
     }
 
     fn reset_stack(&mut self) {
         self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        // Whatever fiber was running, the next script starts fresh in the main one.
+        self.ready.clear();
+        self.blocked_fibers = 0;
+        self.current_fiber = self.main_fiber;
+        let main = self.main_fiber.as_fiber().expect("The main fiber is a fiber");
+        main.stack.borrow_mut().clear();
+        main.frames.borrow_mut().clear();
+        main.upvalues.borrow_mut().clear();
+        main.state.set(FiberState::Running);
+    }
+
+    // A runtime error raised by the VM. run() turns it into an Error instance and throws it,
+    // so it only stops the script when nothing catches it.
+    fn runtime_error(&mut self, message: impl Display) -> anyhow::Error {
+        anyhow!(message.to_string())
     }
 
+    // Build the report for an uncaught exception together with a stack trace, innermost call first:
+    /*
+        Expected 2 arguments but got 1.
+        [line 4] in inner()
+        [line 7] in outer()
+        [line 9] in script
+    */
+    fn stack_trace(&mut self, message: impl Display) -> anyhow::Error {
+        let mut report = message.to_string();
+        for frame in self.frames.iter().rev() {
+            let closure = frame.closure.as_closure().expect("Frames always run closures");
+            let function = closure.function.as_function().expect("Closures always wrap functions");
+            // ip already points past the failing instruction.
+            let line = function.chunk.lines()[frame.ip.saturating_sub(1)];
+            match function.name {
+                Some(name) => report.push_str(&format!("\n[line {}] in {}()", line, *name)),
+                None => report.push_str(&format!("\n[line {}] in script", line)),
+            }
+        }
+        for frame in mem::take(&mut self.frames) {
+            self.abandon_frame(&frame);
+        }
+        self.reset_stack();
+        anyhow!(report)
+    }
+
+    // The operands are popped before the operator runs, which is fine even when the result
+    // is a new bignum: the operator copied what it needed out of them.
     fn binary_op_with_check<F>(&mut self, op: F) -> Result<()>
     where
-        F: FnOnce(Value, Value) -> Result<Value, String>,
+        F: FnOnce(Value, Value) -> Result<Computed, String>,
     {
         let b = self.pop()?;
         let a = self.pop()?;
         match op(a, b) {
-            Ok(result) => self.push(result)?,
-            Err(error) => bail!("{}", error),
+            Ok(result) => self.push_computed(result)?,
+            Err(error) => return Err(self.runtime_error(error)),
         }
         Ok(())
     }
 
+    fn push_computed(&mut self, result: Computed) -> Result<()> {
+        let value = match result {
+            Computed::Value(value) => value,
+            Computed::Big(big) => self.big_int(big),
+        };
+        self.push(value)
+    }
+
+    // Every int that fits in 64 bits is a Value::Int, so this only allocates for really big ones:
+    /*
+        9223372036854775807 + 1  ->  bignum 9223372036854775808
+        9223372036854775808 - 1  ->  int 9223372036854775807
+    */
+    pub(crate) fn big_int(&mut self, value: BigInt) -> Value {
+        match value.to_i64() {
+            Some(n) => Value::Int(n),
+            None => Value::Obj(self.alloc(Obj::BigInt(ObjBigInt { value }))),
+        }
+    }
+
     fn debug_trace_execution(&self) {
         // Print current stack state
         print!("          ");
@@ -198,9 +1954,7 @@ impl VM {
         println!();
         
         // Disassemble current instruction
-        if let Some(chunk) = &self.chunk {
-            disassemble_instruction(chunk, self.ip);
-        }
+        disassemble_instruction(self.chunk(), self.frame().ip);
     }
 }
 
@@ -0,0 +1,188 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+// Every script in tests/lox says what running it should print, in comments:
+/*
+    print 1 + 2;          // expect: 3
+    print nothing;        // expect runtime error: Undefined variable 'nothing'.
+    var = 1;              // expect compile error: [line 3] Error at '=': Expect variable name.
+    match (1) { ... }     // expect warning: [line 4] Warning at '2': Unreachable match arm.
+*/
+// The script is run once normally and once with --stress-gc, which must not change anything.
+// Helper modules that scripts import live in tests/lox/lib and are not run on their own.
+
+const EXPECT: &str = "// expect: ";
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error: ";
+const EXPECT_COMPILE_ERROR: &str = "// expect compile error: ";
+const EXPECT_WARNING: &str = "// expect warning: ";
+
+fn script_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("lox").join(format!("{}.lox", name))
+}
+
+fn lox() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_clox_rust"))
+}
+
+fn lines(bytes: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(bytes).lines().map(str::to_string).collect()
+}
+
+fn expectations(source: &str, prefix: &str) -> Vec<String> {
+    source
+        .lines()
+        .filter_map(|line| line.find(prefix).map(|start| line[start + prefix.len()..].to_string()))
+        .collect()
+}
+
+fn check(name: &str, source: &str, output: &Output, flags: &str) {
+    let stdout = lines(&output.stdout);
+    let stderr = lines(&output.stderr);
+    let context = format!("{}.lox {}\nstdout:\n{}\nstderr:\n{}", name, flags, stdout.join("\n"), stderr.join("\n"));
+
+    assert_eq!(stdout, expectations(source, EXPECT), "unexpected output from {}", context);
+
+    let runtime_errors = expectations(source, EXPECT_RUNTIME_ERROR);
+    let compile_errors = expectations(source, EXPECT_COMPILE_ERROR);
+    let expected_code = if !compile_errors.is_empty() {
+        65
+    } else if !runtime_errors.is_empty() {
+        70
+    } else {
+        0
+    };
+    assert_eq!(output.status.code(), Some(expected_code), "unexpected exit code from {}", context);
+
+    // A runtime error is the first thing on stderr, the stack trace follows it.
+    let reported: Vec<&String> = stderr.iter().filter(|line| line.starts_with("Runtime error: ")).collect();
+    let expected: Vec<String> = runtime_errors.iter().map(|message| format!("Runtime error: {}", message)).collect();
+    assert_eq!(reported, expected.iter().collect::<Vec<_>>(), "unexpected runtime error from {}", context);
+
+    // Every compile error has to be expected, so that one mistake can't hide behind a cascade of others.
+    let reported: Vec<&String> = stderr.iter().filter(|line| line.contains("] Error")).collect();
+    assert_eq!(reported, compile_errors.iter().collect::<Vec<_>>(), "unexpected compile errors from {}", context);
+
+    let warnings: Vec<&String> = stderr.iter().filter(|line| line.contains("] Warning")).collect();
+    let expected = expectations(source, EXPECT_WARNING);
+    assert_eq!(warnings, expected.iter().collect::<Vec<_>>(), "unexpected warnings from {}", context);
+}
+
+fn run_script(name: &str) {
+    let path = script_path(name);
+    let source = fs::read_to_string(&path).expect("The test script exists");
+    for flags in [&[][..], &["--stress-gc"][..]] {
+        let output = lox().args(flags).arg(&path).output().expect("The interpreter runs");
+        check(name, &source, &output, &flags.join(" "));
+    }
+}
+
+// Feed lines to the REPL, one at a time like a user typing them, and return what it printed.
+fn run_repl(lines_in: &[&str]) -> (Vec<String>, Vec<String>) {
+    let mut child = lox()
+        .current_dir(script_path("lib/x").parent().expect("tests/lox/lib"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("The interpreter runs");
+    let mut stdin = child.stdin.take().expect("Piped");
+    for line in lines_in {
+        writeln!(stdin, "{}", line).expect("The REPL reads its input");
+    }
+    drop(stdin);
+    let output = child.wait_with_output().expect("The REPL exits at the end of its input");
+    (lines(&output.stdout), lines(&output.stderr))
+}
+
+
+macro_rules! lox_tests {
+    ($($name:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $name() {
+                run_script(stringify!($name));
+            }
+        )*
+    };
+}
+
+lox_tests! {
+    strings,
+    statements,
+    statement_errors,
+    globals,
+    locals,
+    local_errors,
+    control_flow,
+    functions,
+    closures,
+    classes,
+    inheritance,
+    natives,
+    garbage_collection,
+    lists,
+    list_bounds,
+    maps,
+    interpolation,
+    string_escape_errors,
+    exceptions,
+    modules,
+    integers,
+    bignums,
+    iteration,
+    generators,
+    fibers,
+    match_patterns,
+    lambdas,
+    parameters,
+    parameter_errors,
+    constants,
+    constant_errors,
+    list_growth,
+}
+
+// Module paths are absolute in these messages, so they are checked here rather than in a script.
+#[test]
+fn import_cycle() {
+    let output = lox().arg(script_path("lib/cycle_a")).output().expect("The interpreter runs");
+    let stderr = lines(&output.stderr);
+    assert_eq!(output.status.code(), Some(70));
+    assert!(stderr[0].starts_with("Runtime error: Import cycle: "), "{:?}", stderr);
+    assert!(stderr[0].ends_with("cycle_a.lox."), "{:?}", stderr);
+    assert!(stderr[0].contains("cycle_b.lox -> "), "{:?}", stderr);
+}
+
+#[test]
+fn failed_import_runs_again() {
+    let (stdout, stderr) = run_repl(&[r#"import "failing.lox";"#, r#"import "failing.lox";"#]);
+    assert_eq!(stdout.iter().filter(|line| line.contains("loading failing")).count(), 2, "{:?}", stdout);
+    let errors: Vec<&String> = stderr.iter().filter(|line| line.starts_with("Runtime error: ")).collect();
+    assert_eq!(errors, ["Runtime error: Uncaught exception: failing module"; 2].iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn unexported_names_are_hidden() {
+    let (_, stderr) = run_repl(&[r#"import "util.lox" as util;"#, "print util.hidden;"]);
+    assert!(stderr[0].starts_with("Runtime error: Module '"), "{:?}", stderr);
+    assert!(stderr[0].ends_with("util.lox' does not export 'hidden'."), "{:?}", stderr);
+}
+
+// Each REPL line is compiled on its own, so only the VM can tell that a global is already a constant.
+#[test]
+fn constants_survive_repl_lines() {
+    let (stdout, stderr) = run_repl(&["const LIMIT = 1;", "const LIMIT = 2;", "LIMIT = 3;", "print LIMIT;"]);
+    let errors: Vec<&str> = stderr.iter().filter(|line| line.starts_with("Runtime error: ")).map(String::as_str).collect();
+    assert_eq!(errors, ["Runtime error: Can't redefine constant 'LIMIT'.", "Runtime error: Can't assign to constant 'LIMIT'."]);
+    // The prompt is printed without a newline, so the output of a line follows it.
+    assert!(stdout.iter().any(|line| line.ends_with("> 1")), "{:?}", stdout);
+}
+
+// Nothing is allocated while the list grows, so only counting its growth can start a collection.
+#[test]
+fn list_growth_counts() {
+    let output = lox().arg("--log-gc").arg(script_path("list_growth")).output().expect("The interpreter runs");
+    let stderr = lines(&output.stderr);
+    assert!(stderr.iter().any(|line| line == "-- gc begin"), "{:?}", stderr);
+}